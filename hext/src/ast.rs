@@ -0,0 +1,296 @@
+use crate::lexer::{Body, FieldKind, Lexer, Token, TokenKind};
+use crate::source::Cursor;
+use crate::{ByteOrder, Error, ErrorKind, Header, Hext, Span};
+
+/// A hext file as a tree of [Node]s, which [Hext::lower] turns into bytes.
+/// [Hext::parse] is the two together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ast {
+	/// The header comes first, after any comments
+	pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+	pub kind: NodeKind,
+	pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeKind {
+	Header(Header),
+	/// A `~` line after the header
+	ByteOrder(ByteOrder),
+	/// A comment without the `#`
+	Comment(String),
+	/// Octets written together, like `DEADBEEF`, which are repeated together
+	Octets(Vec<u8>),
+	Bits(Vec<bool>),
+	/// What follows the `=` of an unsized decimal
+	Unsized(String),
+	/// A sized field like `u16be=4`, which is split into `16`, the byte
+	/// order, and `4`
+	Field {
+		kind: FieldKind,
+		width: String,
+		byteorder: Option<ByteOrder>,
+		value: String,
+	},
+	Literal(Vec<u8>),
+	Label(String),
+	/// The node's span is the token that opened the block and `close` is the
+	/// span of the `}`
+	Block {
+		opener: Opener,
+		nodes: Vec<Node>,
+		close: Span,
+	},
+	/// Repeat the last thing that can be, which is anything that writes
+	/// something, this many times in all
+	Repeat(String),
+	Constant {
+		name: String,
+		value: String,
+	},
+	/// A directive or macro invocation
+	Directive {
+		name: String,
+		arguments: Option<String>,
+	},
+	Macro {
+		definition: String,
+		body: Body,
+	},
+	If {
+		condition: Option<String>,
+		then: Body,
+		otherwise: Option<Body>,
+	},
+}
+
+/// What opened a block
+#[derive(Clone, Debug, PartialEq)]
+pub enum Opener {
+	/// `{`, which only groups what's inside
+	Plain,
+	/// `@name{`, which defines `name.end` when it closes
+	Named(String),
+	/// `u16{` or `u32be+4{`, with what's between the `u` and the brace
+	Length(String),
+	/// `varint{` or `varint-1{`, with the adjustment
+	Varint(String),
+}
+
+impl Ast {
+	/// Lex and parse a whole file, which has to start with a header
+	pub fn parse(source: &str) -> Result<Self, Error> {
+		match Self::parse_partial(source) {
+			(_, Some(error)) => Err(error),
+			(ast, None) => Ok(ast),
+		}
+	}
+
+	/// Parse what's after the header, or something without one like an
+	/// included file
+	pub(crate) fn parse_nested(chars: Cursor) -> Result<Vec<Node>, Error> {
+		match Self::nested_partial(chars) {
+			(_, Some(error)) => Err(error),
+			(nodes, None) => Ok(nodes),
+		}
+	}
+
+	/// Parse as much of a file as we can. If there's an error, it comes back
+	/// with everything before it so that can be lowered first. That way an
+	/// error is found in the order it was written, even when it's in an
+	/// include or macro that comes before a mistake in the file itself.
+	pub(crate) fn parse_partial(source: &str) -> (Self, Option<Error>) {
+		let (nodes, error) = Self::nodes(Lexer::new(source), true);
+		(Self { nodes }, error)
+	}
+
+	/// [Ast::parse_nested] as [Ast::parse_partial] is to [Ast::parse]
+	pub(crate) fn nested_partial(chars: Cursor) -> (Vec<Node>, Option<Error>) {
+		Self::nodes(Lexer::from_cursor(chars), false)
+	}
+
+	/// The nodes up to the first error, if there is one. Blocks still open
+	/// at the error are cut short there.
+	fn nodes(lexer: Lexer, header: bool) -> (Vec<Node>, Option<Error>) {
+		let mut nodes = vec![];
+		// Blocks that haven't been closed yet, innermost last, with what's
+		// in them so far
+		let mut open: Vec<(Opener, Span, Vec<Node>)> = vec![];
+
+		let error = Self::fill(lexer, header, &mut nodes, &mut open).err();
+		while let Some((opener, opened, inside)) = open.pop() {
+			let block = Node {
+				kind: NodeKind::Block {
+					opener,
+					nodes: inside,
+					close: Span::default(),
+				},
+				span: opened,
+			};
+			match open.last_mut() {
+				Some((_, _, nodes)) => nodes.push(block),
+				None => nodes.push(block),
+			}
+		}
+
+		(nodes, error)
+	}
+
+	fn fill(
+		lexer: Lexer,
+		header: bool,
+		nodes: &mut Vec<Node>,
+		open: &mut Vec<(Opener, Span, Vec<Node>)>,
+	) -> Result<(), Error> {
+		let mut needs_header = header;
+
+		for token in lexer {
+			let Token { kind, span } = token?;
+			let current = match open.last_mut() {
+				Some((_, _, nodes)) => nodes,
+				None => &mut *nodes,
+			};
+
+			if let TokenKind::Comment(comment) = kind {
+				current.push(Node {
+					kind: NodeKind::Comment(comment),
+					span,
+				});
+				continue;
+			}
+
+			// The text of a ~ line starts after the ~
+			let line = Span {
+				start: span.start + 1,
+				column: span.column + 1,
+				..span
+			};
+			if std::mem::take(&mut needs_header) {
+				let TokenKind::Header(header) = kind else {
+					let first = Span {
+						end: span.start + 1,
+						..span
+					};
+					return Err(Error::new(ErrorKind::NoHeader, first));
+				};
+
+				current.push(Node {
+					kind: NodeKind::Header(Hext::parse_header(&header, line)?),
+					span,
+				});
+				continue;
+			}
+
+			let opener = match kind {
+				TokenKind::Open => Some(Opener::Plain),
+				TokenKind::NamedBlock(ref name) => Some(Opener::Named(name.clone())),
+				TokenKind::LengthBlock(ref spec) => Some(Opener::Length(spec.clone())),
+				TokenKind::Varint(ref adjust) => Some(Opener::Varint(adjust.clone())),
+				_ => None,
+			};
+			if let Some(opener) = opener {
+				open.push((opener, span, vec![]));
+				continue;
+			}
+
+			let kind = match kind {
+				TokenKind::Header(line_text) => {
+					NodeKind::ByteOrder(Hext::parse_byteorder_change(&line_text, line)?)
+				}
+				TokenKind::Octet(octet) => match current.last_mut() {
+					// Octets written together are one node
+					Some(Node {
+						kind: NodeKind::Octets(octets),
+						span: last,
+					}) if last.end == span.start => {
+						octets.push(octet);
+						last.end = span.end;
+						continue;
+					}
+					_ => NodeKind::Octets(vec![octet]),
+				},
+				TokenKind::Bits(bits) => NodeKind::Bits(bits),
+				TokenKind::Unsized(value) => NodeKind::Unsized(value),
+				TokenKind::Field(kind, text) => {
+					let Some((width, value)) = text.split_once('=') else {
+						let kind = match kind {
+							FieldKind::Unsigned => ErrorKind::InvalidDecimal(text),
+							FieldKind::Signed => ErrorKind::InvalidSignedDecimal(text),
+							FieldKind::Float => ErrorKind::InvalidFloat(text),
+						};
+						return Err(Error::new(kind, span));
+					};
+
+					let (width, byteorder) = Hext::split_byteorder(width);
+					NodeKind::Field {
+						kind,
+						width: width.to_string(),
+						byteorder,
+						value: value.to_string(),
+					}
+				}
+				TokenKind::Literal(literal) => NodeKind::Literal(literal),
+				TokenKind::Label(name) => NodeKind::Label(name),
+				TokenKind::Close => {
+					let (opener, opened, inside) = open
+						.pop()
+						.ok_or_else(|| Error::new(ErrorKind::UnmatchedBlockClose, span))?;
+
+					let block = Node {
+						kind: NodeKind::Block {
+							opener,
+							nodes: inside,
+							close: span,
+						},
+						span: opened,
+					};
+					match open.last_mut() {
+						Some((_, _, nodes)) => nodes.push(block),
+						None => nodes.push(block),
+					}
+					continue;
+				}
+				TokenKind::Repeat(count) => NodeKind::Repeat(count),
+				TokenKind::Constant(definition) => match definition.split_once('=') {
+					Some((name, value)) => NodeKind::Constant {
+						name: name.trim().to_string(),
+						value: value.trim().to_string(),
+					},
+					None => {
+						return Err(Error::new(
+							ErrorKind::InvalidConstant(format!("${}", definition.trim())),
+							span,
+						))
+					}
+				},
+				TokenKind::Directive { name, arguments } => NodeKind::Directive { name, arguments },
+				TokenKind::Macro { definition, body } => NodeKind::Macro { definition, body },
+				TokenKind::If {
+					condition,
+					then,
+					otherwise,
+				} => NodeKind::If {
+					condition,
+					then,
+					otherwise,
+				},
+				TokenKind::Comment(_)
+				| TokenKind::Open
+				| TokenKind::NamedBlock(_)
+				| TokenKind::LengthBlock(_)
+				| TokenKind::Varint(_) => unreachable!(),
+			};
+
+			current.push(Node { kind, span });
+		}
+
+		match open.last() {
+			Some((_, opened, _)) => Err(Error::new(ErrorKind::UnclosedBlock, *opened)),
+			None => Ok(()),
+		}
+	}
+}
@@ -0,0 +1,153 @@
+/// The checksums that can be written with a directive like `!crc32(@data)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Checksum {
+	Crc(Crc),
+	Adler32,
+	Fletcher16,
+	Fletcher32,
+	/// The one's complement sum from RFC 1071, used by IPv4, TCP, and UDP
+	Internet,
+}
+
+/// The parameters of a CRC, named the way the CRC RevEng catalogue names them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Crc {
+	width: u32,
+	poly: u64,
+	init: u64,
+	reflect: bool,
+	xorout: u64,
+}
+
+impl Crc {
+	const fn new(width: u32, poly: u64, init: u64, reflect: bool, xorout: u64) -> Self {
+		Self {
+			width,
+			poly,
+			init,
+			reflect,
+			xorout,
+		}
+	}
+
+	fn compute(&self, data: &[u8]) -> u64 {
+		let mask = u64::MAX >> (64 - self.width);
+		let top = 1 << (self.width - 1);
+
+		// The reflected form is the same CRC computed from the other end, so
+		// flip the polynomial and shift the other way instead of flipping
+		// every byte.
+		if self.reflect {
+			let poly = self.poly.reverse_bits() >> (64 - self.width);
+			let mut crc = self.init.reverse_bits() >> (64 - self.width);
+
+			for &byte in data {
+				crc ^= byte as u64;
+				for _ in 0..8 {
+					crc = if crc & 1 == 1 {
+						(crc >> 1) ^ poly
+					} else {
+						crc >> 1
+					};
+				}
+			}
+
+			(crc ^ self.xorout) & mask
+		} else {
+			let mut crc = self.init;
+
+			for &byte in data {
+				for bit in (0..8).rev() {
+					let incoming = (byte >> bit) & 1 == 1;
+					let outgoing = crc & top != 0;
+
+					crc = (crc << 1) & mask;
+					if incoming != outgoing {
+						crc ^= self.poly;
+					}
+				}
+			}
+
+			(crc ^ self.xorout) & mask
+		}
+	}
+}
+
+impl Checksum {
+	pub fn from_name(name: &str) -> Option<Self> {
+		let crc = |width, poly, init, reflect, xorout| {
+			Some(Checksum::Crc(Crc::new(width, poly, init, reflect, xorout)))
+		};
+
+		match name {
+			"crc8" => crc(8, 0x07, 0, false, 0),
+			"crc8-maxim" => crc(8, 0x31, 0, true, 0),
+			"crc16" => crc(16, 0x8005, 0, true, 0),
+			"crc16-ccitt" => crc(16, 0x1021, 0xFFFF, false, 0),
+			"crc16-xmodem" => crc(16, 0x1021, 0, false, 0),
+			"crc16-modbus" => crc(16, 0x8005, 0xFFFF, true, 0),
+			"crc32" => crc(32, 0x04C11DB7, 0xFFFFFFFF, true, 0xFFFFFFFF),
+			"crc32c" => crc(32, 0x1EDC6F41, 0xFFFFFFFF, true, 0xFFFFFFFF),
+			"crc32-bzip2" => crc(32, 0x04C11DB7, 0xFFFFFFFF, false, 0xFFFFFFFF),
+			"crc32-mpeg2" => crc(32, 0x04C11DB7, 0xFFFFFFFF, false, 0),
+			"crc64" => crc(64, 0x42F0E1EBA9EA3693, 0, false, 0),
+			"crc64-xz" => crc(64, 0x42F0E1EBA9EA3693, u64::MAX, true, u64::MAX),
+			"adler32" => Some(Checksum::Adler32),
+			"fletcher16" => Some(Checksum::Fletcher16),
+			"fletcher32" => Some(Checksum::Fletcher32),
+			"internet" => Some(Checksum::Internet),
+			_ => None,
+		}
+	}
+
+	/// The width of the checksum in bits
+	pub fn width(&self) -> u32 {
+		match self {
+			Checksum::Crc(crc) => crc.width,
+			Checksum::Adler32 | Checksum::Fletcher32 => 32,
+			Checksum::Fletcher16 | Checksum::Internet => 16,
+		}
+	}
+
+	pub fn compute(&self, data: &[u8]) -> u128 {
+		match self {
+			Checksum::Crc(crc) => crc.compute(data) as u128,
+			Checksum::Adler32 => {
+				let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+					let a = (a + byte as u32) % 65521;
+					(a, (b + a) % 65521)
+				});
+
+				((b << 16) | a) as u128
+			}
+			Checksum::Fletcher16 => {
+				let (a, b) = data.iter().fold((0u16, 0u16), |(a, b), &byte| {
+					let a = (a + byte as u16) % 255;
+					(a, (b + a) % 255)
+				});
+
+				((b << 8) | a) as u128
+			}
+			Checksum::Fletcher32 => {
+				// Over little endian words, with a zero on the end if need be
+				let (a, b) = data.chunks(2).fold((0u32, 0u32), |(a, b), word| {
+					let word = word[0] as u32 | (*word.get(1).unwrap_or(&0) as u32) << 8;
+					let a = (a + word) % 65535;
+					(a, (b + a) % 65535)
+				});
+
+				((b << 16) | a) as u128
+			}
+			Checksum::Internet => {
+				// Over big endian words, with a zero on the end if need be. The
+				// carry goes back in after every word so the sum never grows.
+				let sum = data.chunks(2).fold(0u32, |sum, word| {
+					let sum = sum + ((word[0] as u32) << 8 | *word.get(1).unwrap_or(&0) as u32);
+					(sum & 0xFFFF) + (sum >> 16)
+				});
+
+				(!sum & 0xFFFF) as u128
+			}
+		}
+	}
+}
@@ -0,0 +1,316 @@
+use crate::schema::{Format, Item, Layout};
+use crate::Hext;
+use std::fmt::Write;
+
+/// Turns bytes back into hext. The output is rows of hex with the offset of
+/// each row in a comment, and runs of printable text as string literals.
+/// Parsing it with [crate::Hext::parse] gives back the same bytes.
+///
+/// Bytes in a [Format] it knows are written field by field instead, with
+/// comments saying what each one is. Anything past what the format covers
+/// is written as rows.
+#[derive(Clone, Debug)]
+pub struct Decompiler {
+	width: usize,
+	strings: usize,
+	offsets: bool,
+	format: Option<Format>,
+	detect: bool,
+}
+
+/// A piece of a row
+enum Segment<'a> {
+	Hex(&'a [u8]),
+	Text(&'a [u8]),
+}
+
+impl Default for Decompiler {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Decompiler {
+	pub fn new() -> Self {
+		Self {
+			width: 16,
+			strings: 4,
+			offsets: true,
+			format: None,
+			detect: true,
+		}
+	}
+
+	/// How many bytes go on each row. Zero is taken as one.
+	pub fn width(mut self, width: usize) -> Self {
+		self.width = width.max(1);
+		self
+	}
+
+	/// The shortest run of printable bytes that is written as a string
+	/// literal. Zero writes everything as hex.
+	pub fn strings(mut self, length: usize) -> Self {
+		self.strings = length;
+		self
+	}
+
+	/// Whether to end each row with a comment giving its offset
+	pub fn offsets(mut self, offsets: bool) -> Self {
+		self.offsets = offsets;
+		self
+	}
+
+	/// Lay the bytes out as `format` whether or not they look like it
+	pub fn format(mut self, format: Format) -> Self {
+		self.format = Some(format);
+		self
+	}
+
+	/// Whether to look for a format the bytes are in when none was given.
+	/// On by default.
+	pub fn detect(mut self, detect: bool) -> Self {
+		self.detect = detect;
+		self
+	}
+
+	pub fn decompile(&self, bytes: &[u8]) -> String {
+		let format = self
+			.format
+			.or_else(|| self.detect.then(|| Format::detect(bytes)).flatten());
+
+		// Should a format get something wrong we'd rather have plain rows
+		// than a file that doesn't give back the bytes
+		if let Some(format) = format {
+			let hext = self.render_layout(&format.describe(bytes), bytes);
+			if Hext::new().parse(&hext).ok().as_deref() == Some(bytes) {
+				return hext;
+			}
+		}
+
+		let mut hext = String::from("~big-endian msb0\n");
+		self.render_rows(&mut hext, bytes, 0, 0, Self::digits(bytes));
+		hext
+	}
+
+	pub(crate) fn render_layout(&self, layout: &Layout, bytes: &[u8]) -> String {
+		let digits = Self::digits(bytes);
+		let mut hext = match layout.little_endian {
+			true => String::from("~little-endian msb0\n"),
+			false => String::from("~big-endian msb0\n"),
+		};
+		let header = hext.len();
+
+		let mut depth = 0;
+		let mut items = layout.items.iter().peekable();
+		while let Some(item) = items.next() {
+			let indent = "\t".repeat(depth);
+
+			match item {
+				Item::Section(title) => {
+					// Sections are spaced apart from what's before them
+					if hext.len() > header {
+						hext.push('\n');
+					}
+					let _ = writeln!(hext, "{}# {}", indent, title);
+				}
+				Item::Field { .. } => {
+					// Comments line up for a run of fields
+					let mut run = vec![item];
+					while let Some(field @ Item::Field { .. }) = items.peek() {
+						run.push(field);
+						items.next();
+					}
+
+					let longest = run
+						.iter()
+						.map(|field| match field {
+							Item::Field { token, .. } => token.len(),
+							_ => 0,
+						})
+						.max()
+						.unwrap_or(0);
+
+					for field in run {
+						if let Item::Field { token, comment } = field {
+							Self::render_line(&mut hext, &indent, token, comment, longest);
+						}
+					}
+				}
+				Item::Open { token, comment } => {
+					Self::render_line(&mut hext, &indent, token, comment, token.len());
+					depth += 1;
+				}
+				Item::Close => {
+					depth = depth.saturating_sub(1);
+					let _ = writeln!(hext, "{}}}", "\t".repeat(depth));
+				}
+				Item::Raw(range) => {
+					self.render_rows(&mut hext, &bytes[range.clone()], range.start, depth, digits);
+				}
+			}
+		}
+
+		if layout.length < bytes.len() {
+			let _ = writeln!(hext, "\n# Past the end of the {}", layout.format.name());
+			self.render_rows(&mut hext, &bytes[layout.length..], layout.length, 0, digits);
+		}
+
+		hext
+	}
+
+	fn render_line(hext: &mut String, indent: &str, token: &str, comment: &str, width: usize) {
+		let _ = match comment.is_empty() {
+			true => writeln!(hext, "{}{}", indent, token),
+			false => writeln!(
+				hext,
+				"{}{:width$} # {}",
+				indent,
+				token,
+				comment,
+				width = width
+			),
+		};
+	}
+
+	/// Write `bytes` as rows. `offset` is where they are in the whole file
+	/// and `digits` is how many hex digits offsets get.
+	fn render_rows(
+		&self,
+		hext: &mut String,
+		bytes: &[u8],
+		offset: usize,
+		depth: usize,
+		digits: usize,
+	) {
+		let rows: Vec<(usize, String)> = self
+			.rows(bytes)
+			.into_iter()
+			.map(|(start, segments)| {
+				let row: Vec<String> = segments.iter().map(Self::render).collect();
+				(offset + start, row.join(" "))
+			})
+			.collect();
+
+		let indent = "\t".repeat(depth);
+		let longest = rows.iter().map(|(_, row)| row.len()).max().unwrap_or(0);
+
+		for (offset, row) in rows {
+			if self.offsets {
+				let _ = writeln!(
+					hext,
+					"{}{:longest$}  # 0x{:0digits$X}",
+					indent,
+					row,
+					offset,
+					longest = longest,
+					digits = digits
+				);
+			} else {
+				let _ = writeln!(hext, "{}{}", indent, row);
+			}
+		}
+	}
+
+	/// Offsets get enough hex digits for the end of the file, and at least four
+	fn digits(bytes: &[u8]) -> usize {
+		format!("{:X}", bytes.len()).len().max(4)
+	}
+
+	/// Split the bytes into rows of at most `width` bytes, each with the
+	/// offset it starts at. Text that runs over the end of a row carries on
+	/// in the next.
+	fn rows<'a>(&self, bytes: &'a [u8]) -> Vec<(usize, Vec<Segment<'a>>)> {
+		let mut rows = vec![];
+		let mut row = vec![];
+		let mut row_start = 0;
+		let mut offset = 0;
+		// What's left of a run of text that didn't fit on the last row
+		let mut text_left = 0;
+
+		while offset < bytes.len() {
+			let room = self.width - (offset - row_start);
+			let text = match text_left {
+				0 => self.text_length(&bytes[offset..]),
+				left => left,
+			};
+
+			if text > 0 {
+				let take = text.min(room);
+				row.push(Segment::Text(&bytes[offset..offset + take]));
+				offset += take;
+				text_left = text - take;
+			} else {
+				// Hex up to the next run of text or the end of the row
+				let mut end = offset + 1;
+				while end < bytes.len()
+					&& end - row_start < self.width
+					&& self.text_length(&bytes[end..]) == 0
+				{
+					end += 1;
+				}
+
+				row.push(Segment::Hex(&bytes[offset..end]));
+				offset = end;
+			}
+
+			if offset - row_start == self.width || offset == bytes.len() {
+				rows.push((row_start, std::mem::take(&mut row)));
+				row_start = offset;
+			}
+		}
+
+		rows
+	}
+
+	/// How long the run of text at the start of `bytes` is, or zero if it's
+	/// too short to be worth writing as a string literal
+	fn text_length(&self, bytes: &[u8]) -> usize {
+		if self.strings == 0 {
+			return 0;
+		}
+
+		let length = bytes.iter().take_while(|byte| is_printable(**byte)).count();
+
+		if length >= self.strings {
+			length
+		} else {
+			0
+		}
+	}
+
+	fn render(segment: &Segment) -> String {
+		match segment {
+			Segment::Hex(bytes) => octets(bytes),
+			Segment::Text(bytes) => literal(bytes),
+		}
+	}
+}
+
+/// Printable ASCII and the whitespace there are escapes for
+pub(crate) fn is_printable(byte: u8) -> bool {
+	matches!(byte, b' '..=b'~' | b'\n' | b'\r' | b'\t')
+}
+
+/// Bytes as spaced out hex, like `4F 0A`
+pub(crate) fn octets(bytes: &[u8]) -> String {
+	let octets: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+	octets.join(" ")
+}
+
+/// Printable bytes as a string literal, escaped the way the parser expects
+pub(crate) fn literal(bytes: &[u8]) -> String {
+	let mut literal = String::from("\"");
+	for &byte in bytes {
+		match byte {
+			b'"' => literal.push_str("\\\""),
+			b'\\' => literal.push_str("\\\\"),
+			b'\n' => literal.push_str("\\n"),
+			b'\r' => literal.push_str("\\r"),
+			b'\t' => literal.push_str("\\t"),
+			byte => literal.push(byte as char),
+		}
+	}
+	literal.push('"');
+
+	literal
+}
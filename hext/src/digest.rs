@@ -0,0 +1,48 @@
+/// The names of the digest directives, which are known even without the
+/// digest feature so we can say why they don't work.
+#[cfg(not(feature = "digest"))]
+pub(crate) const NAMES: [&str; 4] = ["md5", "sha1", "sha256", "blake3"];
+
+/// The cryptographic hashes that can be written with a directive like
+/// `!sha256(@payload)`. Only available with the digest feature.
+#[cfg(feature = "digest")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Digest {
+	Md5,
+	Sha1,
+	Sha256,
+	Blake3,
+}
+
+#[cfg(feature = "digest")]
+impl Digest {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"md5" => Some(Digest::Md5),
+			"sha1" => Some(Digest::Sha1),
+			"sha256" => Some(Digest::Sha256),
+			"blake3" => Some(Digest::Blake3),
+			_ => None,
+		}
+	}
+
+	/// The width of the digest in bits
+	pub fn width(&self) -> u32 {
+		match self {
+			Digest::Md5 => 128,
+			Digest::Sha1 => 160,
+			Digest::Sha256 | Digest::Blake3 => 256,
+		}
+	}
+
+	pub fn compute(&self, data: &[u8]) -> Vec<u8> {
+		use sha2::Digest as _;
+
+		match self {
+			Digest::Md5 => md5::Md5::digest(data).to_vec(),
+			Digest::Sha1 => sha1::Sha1::digest(data).to_vec(),
+			Digest::Sha256 => sha2::Sha256::digest(data).to_vec(),
+			Digest::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+		}
+	}
+}
@@ -1,8 +1,95 @@
+use crate::Span;
 use std::error::Error as ErrorTrait;
 use std::fmt;
 
+/// What went wrong, and where in the source it went wrong.
 #[derive(Debug, PartialEq)]
-pub enum Error {
+pub struct Error {
+	kind: ErrorKind,
+	span: Span,
+	/// Boxed as it's rarely there and would make every Result huge
+	location: Option<Box<Location>>,
+}
+
+/// The file an error is in and how we got there
+#[derive(Debug, PartialEq)]
+struct Location {
+	file: Option<String>,
+	includes: Vec<Include>,
+}
+
+/// An `!include` directive or macro invocation that led to where an error is
+#[derive(Clone, Debug, PartialEq)]
+pub struct Include {
+	/// The name of the file the directive is in, if it has one
+	pub file: Option<String>,
+	pub span: Span,
+	/// The name of the macro, if the directive invoked one. The error is then
+	/// in the body of the macro, or something it led to.
+	pub macro_name: Option<String>,
+}
+
+impl Error {
+	pub(crate) fn new(kind: ErrorKind, span: Span) -> Self {
+		Self {
+			kind,
+			span,
+			location: None,
+		}
+	}
+
+	/// Say which file the error is in and how we got there. `span` replaces
+	/// the error's own, which may have been in the expansion of a macro.
+	pub(crate) fn locate(self, span: Span, file: Option<String>, includes: Vec<Include>) -> Self {
+		Self {
+			span,
+			location: Some(Box::new(Location { file, includes })),
+			..self
+		}
+	}
+
+	pub fn kind(&self) -> &ErrorKind {
+		&self.kind
+	}
+
+	/// The span of the token that caused the error
+	pub fn span(&self) -> Span {
+		self.span
+	}
+
+	/// The name of the file the error is in, if it has one
+	pub fn file(&self) -> Option<&str> {
+		self.location.as_ref()?.file.as_deref()
+	}
+
+	/// The includes and macro invocations that led to where the error is,
+	/// outermost first. Empty if the error is in the source given to
+	/// [crate::Hext::parse] and not in the body of a macro.
+	pub fn includes(&self) -> &[Include] {
+		match &self.location {
+			Some(location) => &location.includes,
+			None => &[],
+		}
+	}
+}
+
+impl ErrorTrait for Error {
+	fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
+		None
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.file() {
+			Some(file) => write!(f, "{}:{}: {}", file, self.span, self.kind),
+			None => write!(f, "{}: {}", self.span, self.kind),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
 	NoHeader,
 	InvalidHeader(InvalidHeaderKind),
 
@@ -13,6 +100,72 @@ pub enum Error {
 	InvalidUnsignedDecimal(String),
 	InvalidBitness(String),
 
+	InvalidFloat(String),
+	InvalidFloatWidth(String),
+
+	InvalidLabel(String),
+	UnalignedLabel(String),
+	DuplicateLabel(String),
+	UndefinedLabel(String),
+	InvalidExpression(String),
+	ExpressionOverflow,
+	DivisionByZero,
+	LabelNotAllowed(String),
+
+	InvalidBlock(String),
+	UnalignedBlock,
+	UnclosedBlock,
+	UnmatchedBlockClose,
+	BlockLengthDoesNotFit(i128),
+	DoesNotFit {
+		expression: String,
+		value: i128,
+		width: u32,
+	},
+
+	UnknownDirective(String),
+	InvalidArguments {
+		directive: String,
+		arguments: String,
+	},
+	UnclosedDirective,
+	BackwardsRegion,
+	ChecksumCycle,
+	DigestUnavailable(String),
+
+	IncludeFailed {
+		path: String,
+		reason: String,
+	},
+	IncludeCycle(String),
+	EmbedOutOfRange(usize),
+	PaddedPast {
+		offset: usize,
+		length: usize,
+	},
+	PadInVarintBlock,
+
+	InvalidConstant(String),
+	DuplicateConstant(String),
+	UndefinedConstant(String),
+
+	NothingToRepeat,
+	InvalidRepeat(String),
+
+	InvalidMacro(String),
+	DuplicateMacro(String),
+	UnclosedMacro(String),
+	UnmatchedEnd,
+	MacroArguments {
+		name: String,
+		expected: usize,
+		found: usize,
+	},
+	MacroRecursion(String),
+
+	UnclosedIf,
+	UnmatchedElse,
+
 	InvalidCharacter(char),
 
 	InvalidEscape(char),
@@ -21,45 +174,190 @@ pub enum Error {
 	GarbageCharacterInBitstream,
 
 	UnalignedBits,
-}
 
-impl ErrorTrait for Error {
-	fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
-		None
-	}
+	FormatChanged,
 }
 
-impl fmt::Display for Error {
+impl fmt::Display for ErrorKind {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
-			Error::NoHeader => write!(f, "The file must start with a header"),
-			Error::InvalidHeader(kind) => write!(f, "{}", kind),
-			Error::InvalidCharacter(c) => write!(f, "'{}' is not valid base16", c),
-			Error::InvalidEscape(c) => write!(f, "\\{} is not a valid escape code", c),
-			Error::UnclosedStringLiteral => {
+			ErrorKind::NoHeader => write!(f, "The file must start with a header"),
+			ErrorKind::InvalidHeader(kind) => write!(f, "{}", kind),
+			ErrorKind::InvalidCharacter(c) => write!(f, "'{}' is not valid base16", c),
+			ErrorKind::InvalidEscape(c) => write!(f, "\\{} is not a valid escape code", c),
+			ErrorKind::UnclosedStringLiteral => {
 				write!(
 					f,
 					"The line or file ended in an unterminated string literal"
 				)
 			}
-			Error::IncompleteOctet => write!(f, "Octet was not complete"),
-			Error::GarbageCharacterInBitstream => write!(
+			ErrorKind::IncompleteOctet => write!(f, "Octet was not complete"),
+			ErrorKind::GarbageCharacterInBitstream => write!(
 				f,
 				"Periods to indicate binary data must be directly followed by that data"
 			),
-			Error::UnalignedBits => write!(f, "Not enough bits to form an octet"),
-			Error::InvalidDecimal(string) => write!(f, "'{}' is not valid decimal", string),
-			Error::InvalidSignedDecimal(value) => {
+			ErrorKind::UnalignedBits => write!(f, "Not enough bits to form an octet"),
+			ErrorKind::FormatChanged => write!(
+				f,
+				"Formatting would have changed what the file means, so it was left alone"
+			),
+			ErrorKind::InvalidDecimal(string) => write!(f, "'{}' is not valid decimal", string),
+			ErrorKind::InvalidSignedDecimal(value) => {
 				write!(f, "'{}' is not valid signed decimal", value)
 			}
-			Error::InvalidUnsignedDecimal(value) => {
+			ErrorKind::InvalidUnsignedDecimal(value) => {
 				write!(f, "'{}' is not valid unsigned decimal", value)
 			}
-			Error::InvalidBitness(bitness) => write!(
+			ErrorKind::InvalidBitness(bitness) => write!(
 				f,
-				"'{}' is not a valid width. Valid widths are 8, 16, 32, and 64",
+				"'{}' is not a valid width. Widths may be anywhere from 1 to 128",
 				bitness
 			),
+			ErrorKind::InvalidFloat(value) => write!(f, "'{}' is not a valid float", value),
+			ErrorKind::InvalidFloatWidth(width) => write!(
+				f,
+				"'{}' is not a valid float width. Floats may be 16, 32, or 64 bits wide",
+				width
+			),
+			ErrorKind::InvalidLabel(name) => write!(
+				f,
+				"'{}' is not a valid label. Labels start with a letter or underscore",
+				name
+			),
+			ErrorKind::UnalignedLabel(name) => {
+				write!(f, "The label '{}' is not on an octet boundary", name)
+			}
+			ErrorKind::DuplicateLabel(name) => {
+				write!(f, "The label '{}' has already been defined", name)
+			}
+			ErrorKind::UndefinedLabel(name) => write!(f, "There is no label called '{}'", name),
+			ErrorKind::InvalidExpression(expression) => {
+				write!(f, "'{}' is not a valid expression", expression)
+			}
+			ErrorKind::ExpressionOverflow => write!(f, "The expression overflowed"),
+			ErrorKind::DivisionByZero => write!(f, "The expression divides by zero"),
+			ErrorKind::LabelNotAllowed(name) => write!(
+				f,
+				"The label '{}' can't be used here as its offset isn't known yet",
+				name
+			),
+			ErrorKind::InvalidBlock(spec) => write!(
+				f,
+				"'{}' is not a valid block length. Try something like u16{{ or varint+2{{",
+				spec
+			),
+			ErrorKind::UnalignedBlock => {
+				write!(
+					f,
+					"Blocks with a length must start and end on an octet boundary"
+				)
+			}
+			ErrorKind::UnclosedBlock => write!(f, "This block was never closed"),
+			ErrorKind::UnmatchedBlockClose => write!(f, "There is no block to close"),
+			ErrorKind::BlockLengthDoesNotFit(length) => write!(
+				f,
+				"The block's length works out to {}, which does not fit in its length field",
+				length
+			),
+			ErrorKind::DoesNotFit {
+				expression,
+				value,
+				width,
+			} => write!(
+				f,
+				"'{}' is {}, which does not fit in {} bits",
+				expression, value, width
+			),
+			ErrorKind::InvalidConstant(definition) => write!(
+				f,
+				"'{}' is not a valid constant. Try something like $WIDTH = 4",
+				definition
+			),
+			ErrorKind::DuplicateConstant(name) => {
+				write!(f, "The constant '{}' has already been defined", name)
+			}
+			ErrorKind::UndefinedConstant(name) => {
+				write!(f, "There is no constant called '{}'", name)
+			}
+			ErrorKind::NothingToRepeat => write!(
+				f,
+				"There is nothing right before this to repeat. Labels and block openings can't be repeated"
+			),
+			ErrorKind::InvalidRepeat(count) => write!(
+				f,
+				"'{}' is not a valid repeat count. Counts can't be negative",
+				count
+			),
+			ErrorKind::InvalidMacro(definition) => write!(
+				f,
+				"'{}' is not a valid macro. Try something like !macro name(a, b), which isn't the name of a directive",
+				definition
+			),
+			ErrorKind::DuplicateMacro(name) => {
+				write!(f, "The macro '{}' has already been defined", name)
+			}
+			ErrorKind::UnclosedMacro(name) => {
+				write!(f, "The macro '{}' is never closed with !end", name)
+			}
+			ErrorKind::UnmatchedEnd => write!(f, "There is nothing for this !end to close"),
+			ErrorKind::MacroArguments {
+				name,
+				expected,
+				found,
+			} => write!(
+				f,
+				"The macro '{}' takes {} arguments but was given {}",
+				name, expected, found
+			),
+			ErrorKind::MacroRecursion(name) => {
+				write!(f, "The macro '{}' invokes itself", name)
+			}
+			ErrorKind::UnclosedIf => write!(f, "This !if is never closed with !end"),
+			ErrorKind::UnmatchedElse => write!(f, "There is no !if for this !else to go with"),
+			ErrorKind::UnknownDirective(name) => {
+				write!(f, "There is no directive called '{}'", name)
+			}
+			ErrorKind::InvalidArguments {
+				directive,
+				arguments,
+			} => write!(
+				f,
+				"'{}' are not valid arguments for !{}",
+				arguments, directive
+			),
+			ErrorKind::UnclosedDirective => {
+				write!(
+					f,
+					"The line ended before the directive's arguments were closed"
+				)
+			}
+			ErrorKind::DigestUnavailable(name) => write!(
+				f,
+				"!{} needs hext to be built with --features digest",
+				name
+			),
+			ErrorKind::IncludeFailed { path, reason } => {
+				write!(f, "Could not include '{}': {}", path, reason)
+			}
+			ErrorKind::EmbedOutOfRange(length) => write!(
+				f,
+				"The slice runs past the end of the file, which is {} octets long",
+				length
+			),
+			ErrorKind::PaddedPast { offset, length } => write!(
+				f,
+				"Can't pad to offset {} as there are already {} octets",
+				offset, length
+			),
+			ErrorKind::PadInVarintBlock => write!(
+				f,
+				"Can't align or pad inside a varint block, as where it ends up isn't known yet"
+			),
+			ErrorKind::IncludeCycle(file) => write!(f, "'{}' ends up including itself", file),
+			ErrorKind::BackwardsRegion => write!(f, "The region ends before it starts"),
+			ErrorKind::ChecksumCycle => {
+				write!(f, "This checksum covers another checksum that covers it")
+			}
 		}
 	}
 }
@@ -73,6 +371,7 @@ pub enum InvalidHeaderKind {
 
 	TwoNegativeKind,
 	InvalidProperty(String),
+	HeaderOnly(String),
 }
 
 impl fmt::Display for InvalidHeaderKind {
@@ -89,12 +388,17 @@ impl fmt::Display for InvalidHeaderKind {
 			InvalidHeaderKind::InvalidProperty(property) => {
 				write!(f, "'{}' is not a valid file property", property)
 			}
+			InvalidHeaderKind::HeaderOnly(property) => write!(
+				f,
+				"'{}' can only be set in the header. Later lines may only change the byte order",
+				property
+			),
 		}
 	}
 }
 
-impl Into<Error> for InvalidHeaderKind {
-	fn into(self) -> Error {
-		Error::InvalidHeader(self)
+impl From<InvalidHeaderKind> for ErrorKind {
+	fn from(kind: InvalidHeaderKind) -> Self {
+		ErrorKind::InvalidHeader(kind)
 	}
 }
@@ -0,0 +1,353 @@
+use crate::{ErrorKind, Hext};
+
+/// Integer arithmetic on numbers, constants, and labels, like
+/// `(HEADER + 4*COUNT)` or `@end-@start`. Constants are filled in as it's
+/// parsed but labels have to wait until every label has been placed.
+///
+/// Comparisons and logic give one for true and zero for false, and
+/// `defined(NAME)` is whether there's a constant called NAME.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Expression {
+	Number(i128),
+	Label(String),
+	/// A constant that isn't defined, which is only an error if it has to be
+	/// worked out. That way `defined(A) && A` is fine without A.
+	Undefined(String),
+	Unary(Unary, Box<Expression>),
+	Binary(Binary, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Unary {
+	Negate,
+	Not,
+	LogicalNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Binary {
+	LogicalOr,
+	LogicalAnd,
+	Or,
+	Xor,
+	And,
+	Equal,
+	NotEqual,
+	Less,
+	LessOrEqual,
+	Greater,
+	GreaterOrEqual,
+	ShiftLeft,
+	ShiftRight,
+	Add,
+	Subtract,
+	Multiply,
+	Divide,
+	Remainder,
+}
+
+impl Binary {
+	/// Operators that bind tighter have a higher precedence. These are C's.
+	fn precedence(&self) -> u8 {
+		match self {
+			Binary::LogicalOr => 1,
+			Binary::LogicalAnd => 2,
+			Binary::Or => 3,
+			Binary::Xor => 4,
+			Binary::And => 5,
+			Binary::Equal | Binary::NotEqual => 6,
+			Binary::Less | Binary::LessOrEqual | Binary::Greater | Binary::GreaterOrEqual => 7,
+			Binary::ShiftLeft | Binary::ShiftRight => 8,
+			Binary::Add | Binary::Subtract => 9,
+			Binary::Multiply | Binary::Divide | Binary::Remainder => 10,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+	Number(u128),
+	Label(String),
+	Constant(String),
+	Operator(&'static str),
+	Open,
+	Close,
+}
+
+impl Expression {
+	/// Parse an expression. `constant` gives the value of a constant, if there
+	/// is one by that name. Constants may be written bare or with a $.
+	pub fn parse<F: Fn(&str) -> Option<i128>>(
+		string: &str,
+		constant: F,
+	) -> Result<Self, ErrorKind> {
+		let invalid = || ErrorKind::InvalidExpression(string.to_string());
+
+		let tokens = Self::tokenize(string).ok_or_else(invalid)?;
+		let mut parser = Parser {
+			tokens: &tokens,
+			position: 0,
+			constant: &constant,
+		};
+
+		let expression = parser.expression(0)?.ok_or_else(invalid)?;
+		if parser.position != tokens.len() {
+			return Err(invalid());
+		}
+
+		Ok(expression)
+	}
+
+	fn tokenize(string: &str) -> Option<Vec<Token>> {
+		let mut tokens = vec![];
+		let mut rest = string;
+
+		loop {
+			rest = rest.trim_start();
+			let Some(c) = rest.chars().next() else {
+				return Some(tokens);
+			};
+
+			let word_end = |rest: &str, dots: bool| {
+				rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || (dots && c == '.')))
+					.unwrap_or(rest.len())
+			};
+
+			let (token, length) = match c {
+				'0'..='9' => {
+					let end = word_end(rest, false);
+					(Token::Number(Hext::parse_magnitude(&rest[..end])?), end)
+				}
+				'@' => {
+					let end = word_end(&rest[1..], true) + 1;
+					let name = &rest[1..end];
+					if !Self::is_label_name(name) {
+						return None;
+					}
+					(Token::Label(name.to_string()), end)
+				}
+				'$' => {
+					let end = word_end(&rest[1..], false) + 1;
+					(Token::Constant(rest[1..end].to_string()), end)
+				}
+				c if c.is_ascii_alphabetic() || c == '_' => {
+					let end = word_end(rest, false);
+					(Token::Constant(rest[..end].to_string()), end)
+				}
+				'(' => (Token::Open, 1),
+				')' => (Token::Close, 1),
+				_ => {
+					// Longer operators first, so << isn't taken for <
+					let operator = [
+						"<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*",
+						"/", "%", "&", "|", "^", "~", "!",
+					]
+					.into_iter()
+					.find(|operator| rest.starts_with(operator))?;
+					(Token::Operator(operator), operator.len())
+				}
+			};
+
+			tokens.push(token);
+			rest = &rest[length..];
+		}
+	}
+
+	/// `end - start + adjust`, for lengths
+	pub fn difference(end: String, start: String, adjust: i128) -> Self {
+		let difference = Expression::Binary(
+			Binary::Subtract,
+			Box::new(Expression::Label(end)),
+			Box::new(Expression::Label(start)),
+		);
+
+		Expression::Binary(
+			Binary::Add,
+			Box::new(difference),
+			Box::new(Expression::Number(adjust)),
+		)
+	}
+
+	pub fn is_label_name(name: &str) -> bool {
+		name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+			&& name
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+	}
+
+	/// The first label the expression refers to, if it refers to any
+	pub fn label(&self) -> Option<&str> {
+		match self {
+			Expression::Number(_) | Expression::Undefined(_) => None,
+			Expression::Label(name) => Some(name),
+			Expression::Unary(_, operand) => operand.label(),
+			Expression::Binary(_, left, right) => left.label().or_else(|| right.label()),
+		}
+	}
+
+	/// `offset` gives the offset of a label, if there is such a label
+	pub fn evaluate<F: Fn(&str) -> Option<usize>>(&self, offset: &F) -> Result<i128, ErrorKind> {
+		let overflow = || ErrorKind::ExpressionOverflow;
+
+		match self {
+			Expression::Number(number) => Ok(*number),
+			Expression::Undefined(name) => Err(ErrorKind::UndefinedConstant(name.clone())),
+			Expression::Label(name) => {
+				let offset = offset(name).ok_or_else(|| ErrorKind::UndefinedLabel(name.clone()))?;
+				i128::try_from(offset).map_err(|_| overflow())
+			}
+			Expression::Unary(unary, operand) => {
+				let operand = operand.evaluate(offset)?;
+
+				match unary {
+					Unary::Negate => operand.checked_neg().ok_or_else(overflow),
+					Unary::Not => Ok(!operand),
+					Unary::LogicalNot => Ok((operand == 0) as i128),
+				}
+			}
+			Expression::Binary(binary, left, right) => {
+				let left = left.evaluate(offset)?;
+
+				// The right side isn't worked out if it doesn't matter, so
+				// `COUNT && 16 / COUNT` is fine when COUNT is zero
+				match binary {
+					Binary::LogicalOr if left != 0 => return Ok(1),
+					Binary::LogicalAnd if left == 0 => return Ok(0),
+					_ => (),
+				}
+				let right = right.evaluate(offset)?;
+
+				let result = match binary {
+					Binary::LogicalOr | Binary::LogicalAnd => Some((right != 0) as i128),
+					Binary::Or => Some(left | right),
+					Binary::Xor => Some(left ^ right),
+					Binary::And => Some(left & right),
+					Binary::Equal => Some((left == right) as i128),
+					Binary::NotEqual => Some((left != right) as i128),
+					Binary::Less => Some((left < right) as i128),
+					Binary::LessOrEqual => Some((left <= right) as i128),
+					Binary::Greater => Some((left > right) as i128),
+					Binary::GreaterOrEqual => Some((left >= right) as i128),
+					Binary::ShiftLeft => u32::try_from(right)
+						.ok()
+						.and_then(|right| left.checked_shl(right))
+						// Bits shifted off the top are lost, which is overflow
+						.filter(|shifted| shifted >> right == left),
+					Binary::ShiftRight => u32::try_from(right)
+						.ok()
+						.and_then(|right| left.checked_shr(right)),
+					Binary::Add => left.checked_add(right),
+					Binary::Subtract => left.checked_sub(right),
+					Binary::Multiply => left.checked_mul(right),
+					Binary::Divide | Binary::Remainder if right == 0 => {
+						return Err(ErrorKind::DivisionByZero)
+					}
+					Binary::Divide => left.checked_div(right),
+					Binary::Remainder => left.checked_rem(right),
+				};
+
+				result.ok_or_else(overflow)
+			}
+		}
+	}
+}
+
+/// Precedence climbing over the tokens of an expression
+struct Parser<'a, F: Fn(&str) -> Option<i128>> {
+	tokens: &'a [Token],
+	position: usize,
+	constant: &'a F,
+}
+
+impl<F: Fn(&str) -> Option<i128>> Parser<'_, F> {
+	fn next(&mut self) -> Option<&Token> {
+		let token = self.tokens.get(self.position);
+		self.position += 1;
+		token
+	}
+
+	/// Parse operators at least as tight as `precedence`. None if the tokens
+	/// don't make an expression.
+	fn expression(&mut self, precedence: u8) -> Result<Option<Expression>, ErrorKind> {
+		let Some(mut left) = self.operand()? else {
+			return Ok(None);
+		};
+
+		while let Some(Token::Operator(operator)) = self.tokens.get(self.position) {
+			let binary = match *operator {
+				"||" => Binary::LogicalOr,
+				"&&" => Binary::LogicalAnd,
+				"|" => Binary::Or,
+				"^" => Binary::Xor,
+				"&" => Binary::And,
+				"==" => Binary::Equal,
+				"!=" => Binary::NotEqual,
+				"<" => Binary::Less,
+				"<=" => Binary::LessOrEqual,
+				">" => Binary::Greater,
+				">=" => Binary::GreaterOrEqual,
+				"<<" => Binary::ShiftLeft,
+				">>" => Binary::ShiftRight,
+				"+" => Binary::Add,
+				"-" => Binary::Subtract,
+				"*" => Binary::Multiply,
+				"/" => Binary::Divide,
+				"%" => Binary::Remainder,
+				_ => return Ok(None),
+			};
+
+			if binary.precedence() < precedence {
+				break;
+			}
+			self.position += 1;
+
+			// Everything is left associative, so the right side may only take
+			// operators that bind tighter than this one
+			let Some(right) = self.expression(binary.precedence() + 1)? else {
+				return Ok(None);
+			};
+			left = Expression::Binary(binary, Box::new(left), Box::new(right));
+		}
+
+		Ok(Some(left))
+	}
+
+	fn operand(&mut self) -> Result<Option<Expression>, ErrorKind> {
+		let unary = |unary, operand: Option<Expression>| {
+			operand.map(|operand| Expression::Unary(unary, Box::new(operand)))
+		};
+
+		Ok(match self.next().cloned() {
+			Some(Token::Number(number)) => Some(Expression::Number(
+				i128::try_from(number).map_err(|_| ErrorKind::ExpressionOverflow)?,
+			)),
+			Some(Token::Label(name)) => Some(Expression::Label(name)),
+			Some(Token::Constant(name))
+				if name == "defined" && self.tokens.get(self.position) == Some(&Token::Open) =>
+			{
+				match (self.next().cloned(), self.next().cloned(), self.next()) {
+					(Some(Token::Open), Some(Token::Constant(name)), Some(Token::Close)) => {
+						Some(Expression::Number((self.constant)(&name).is_some() as i128))
+					}
+					_ => None,
+				}
+			}
+			Some(Token::Constant(name)) => match (self.constant)(&name) {
+				Some(value) => Some(Expression::Number(value)),
+				None => Some(Expression::Undefined(name)),
+			},
+			Some(Token::Operator("-")) => unary(Unary::Negate, self.operand()?),
+			Some(Token::Operator("~")) => unary(Unary::Not, self.operand()?),
+			Some(Token::Operator("!")) => unary(Unary::LogicalNot, self.operand()?),
+			Some(Token::Operator("+")) => self.operand()?,
+			Some(Token::Open) => {
+				let inner = self.expression(0)?;
+				match self.next() {
+					Some(Token::Close) => inner,
+					_ => None,
+				}
+			}
+			_ => None,
+		})
+	}
+}
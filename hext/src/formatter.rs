@@ -0,0 +1,389 @@
+use crate::ast::{Ast, Node, NodeKind};
+use crate::lexer::{Body, Lexer, Token, TokenKind};
+use crate::source::{Cursor, Span};
+use crate::{BitOrder, ByteOrder, Error, ErrorKind, Hext, NegativeKind};
+
+/// Rewrites hext source in one layout. Blocks are indented with a tab per
+/// level, tokens are a space apart, trailing comments line up with those on
+/// the lines around them, and the header lists its properties in the same
+/// order every time. Where things go from line to line is left alone, as are
+/// comments, macro bodies, and the branches of an `!if`.
+///
+/// Either way the source describes the same bytes. Files without a header,
+/// like ones that are included, can be formatted too.
+#[derive(Clone, Debug, Default)]
+pub struct Formatter {
+	group: Option<usize>,
+}
+
+/// A line of output
+#[derive(Default)]
+struct Line {
+	depth: usize,
+	code: Vec<String>,
+	comment: Option<String>,
+	/// Whether there was a blank line before this one
+	spaced: bool,
+}
+
+impl Formatter {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Write runs of octets in groups of this many, so two gives `DEAD BEEF`.
+	/// Zero is taken as one. Without this octets are grouped as they were
+	/// written.
+	pub fn group(mut self, octets: usize) -> Self {
+		self.group = Some(octets.max(1));
+		self
+	}
+
+	/// Format `source`, which has to be valid hext. Errors are ones that
+	/// [Hext::parse] would have found without looking at what the file means,
+	/// like a stray character or a block that isn't closed, and
+	/// [ErrorKind::FormatChanged] if the formatted file wouldn't mean the same
+	/// as the original.
+	pub fn format(&self, source: &str) -> Result<String, Error> {
+		let nodes = Self::nodes(source)?;
+		let tokens = Lexer::new(source).collect::<Result<Vec<Token>, Error>>()?;
+
+		let formatted = self.render(&self.lines(source, &tokens));
+
+		match Self::nodes(&formatted) {
+			Ok(formatted_nodes) if Self::same(&nodes, &formatted_nodes) => Ok(formatted),
+			_ => Err(Error::new(
+				ErrorKind::FormatChanged,
+				Span {
+					end: source.len(),
+					..Cursor::new(source, 0).mark()
+				},
+			)),
+		}
+	}
+
+	/// Parse the source with its header if it has one
+	fn nodes(source: &str) -> Result<Vec<Node>, Error> {
+		let header = Lexer::new(source)
+			.find(|token| {
+				!matches!(
+					token,
+					Ok(Token {
+						kind: TokenKind::Comment(_),
+						..
+					})
+				)
+			})
+			.is_some_and(|token| {
+				matches!(
+					token,
+					Ok(Token {
+						kind: TokenKind::Header(_),
+						..
+					})
+				)
+			});
+
+		match header {
+			true => Ast::parse(source).map(|ast| ast.nodes),
+			false => Ast::parse_nested(Cursor::new(source, 0)),
+		}
+	}
+
+	fn lines(&self, source: &str, tokens: &[Token]) -> Vec<Line> {
+		let mut lines: Vec<Line> = vec![];
+		let mut line = Line::default();
+		let mut depth = 0usize;
+		let mut header = true;
+		let mut end = 0;
+
+		let mut index = 0;
+		while let Some(token) = tokens.get(index) {
+			// Like a comment in the middle of a run of bits, which is kept as
+			// it was written
+			if token.span.start < end {
+				index += 1;
+				continue;
+			}
+
+			let gap = &source[end..token.span.start];
+			let newlines = gap.matches('\n').count();
+			if newlines > 0 && (!line.code.is_empty() || line.comment.is_some()) {
+				lines.push(std::mem::take(&mut line));
+				line.spaced = newlines > 1;
+			}
+
+			if token.kind == TokenKind::Close {
+				depth = depth.saturating_sub(1);
+			}
+			if line.code.is_empty() && line.comment.is_none() {
+				line.depth = depth;
+			}
+
+			let text = source[token.span.range()].trim_end();
+			end = token.span.end;
+			index += 1;
+
+			let code = match &token.kind {
+				TokenKind::Comment(comment) => {
+					line.comment = Some(format!("#{}", comment.trim_end()));
+					continue;
+				}
+				TokenKind::Header(properties) if header => {
+					Self::header(properties, token.span).unwrap_or_else(|| text.to_string())
+				}
+				TokenKind::Header(properties) => {
+					match Hext::parse_byteorder_change(properties, token.span) {
+						Ok(byteorder) => format!("~{}", Self::byteorder(byteorder)),
+						Err(_) => text.to_string(),
+					}
+				}
+				TokenKind::Octet(_) => {
+					// The rest of the run of octets on this line
+					let mut run = vec![token];
+					while let Some(next) = tokens.get(index) {
+						let between = &source[end..next.span.start];
+						if !matches!(next.kind, TokenKind::Octet(_)) || between.contains('\n') {
+							break;
+						}
+
+						run.push(next);
+						end = next.span.end;
+						index += 1;
+					}
+
+					let repeated = tokens[index..]
+						.iter()
+						.find(|token| !matches!(token.kind, TokenKind::Comment(_)))
+						.is_some_and(|token| matches!(token.kind, TokenKind::Repeat(_)));
+					line.code.extend(self.octets(&run, repeated));
+					continue;
+				}
+				TokenKind::Constant(definition) => match definition.split_once('=') {
+					Some((name, value)) => format!("${} = {}", name.trim(), value.trim()),
+					None => text.to_string(),
+				},
+				TokenKind::Repeat(count) => format!("* {}", count),
+				// Bodies are kept as they are, up to the !end after them
+				TokenKind::Macro { body, .. } => Self::verbatim(source, token, body, &mut end),
+				TokenKind::If {
+					then, otherwise, ..
+				} => Self::verbatim(source, token, otherwise.as_ref().unwrap_or(then), &mut end),
+				_ => text.to_string(),
+			};
+
+			header = false;
+			line.code.push(code);
+
+			if matches!(
+				token.kind,
+				TokenKind::Open
+					| TokenKind::NamedBlock(_)
+					| TokenKind::LengthBlock(_)
+					| TokenKind::Varint(_)
+			) {
+				depth += 1;
+			}
+		}
+
+		if !line.code.is_empty() || line.comment.is_some() {
+			lines.push(line);
+		}
+
+		lines
+	}
+
+	/// The source from `token` to the `!end` after `last`, which is the last
+	/// body it has
+	fn verbatim(source: &str, token: &Token, last: &Body, end: &mut usize) -> String {
+		*end = last.span.end + "!end".len();
+		source[token.span.start..*end].to_string()
+	}
+
+	/// A run of octets on one line. Those written together are repeated
+	/// together, so if the run is `repeated` its last group is left whole.
+	fn octets(&self, run: &[&Token], repeated: bool) -> Vec<String> {
+		// The groups as they were written
+		let mut groups: Vec<Vec<u8>> = vec![];
+		let mut last_end = None;
+		for token in run {
+			let TokenKind::Octet(octet) = token.kind else {
+				continue;
+			};
+
+			match groups.last_mut() {
+				Some(group) if last_end == Some(token.span.start) => group.push(octet),
+				_ => groups.push(vec![octet]),
+			}
+			last_end = Some(token.span.end);
+		}
+
+		if let Some(size) = self.group {
+			let kept = match repeated {
+				true => groups.pop(),
+				false => None,
+			};
+
+			let octets: Vec<u8> = groups.concat();
+			groups = octets.chunks(size).map(<[u8]>::to_vec).collect();
+			groups.extend(kept);
+		}
+
+		groups
+			.iter()
+			.map(|group| group.iter().map(|octet| format!("{:02X}", octet)).collect())
+			.collect()
+	}
+
+	/// The header with its properties in order, or None if it isn't valid
+	fn header(properties: &str, span: Span) -> Option<String> {
+		let header = Hext::parse_header(properties, span).ok()?;
+
+		let mut line = format!("~{}", Self::byteorder(header.byteorder));
+		line.push_str(match header.bitorder {
+			BitOrder::Msb0 => " msb0",
+			BitOrder::Lsb0 => " lsb0",
+		});
+		line.push_str(match header.negativekind {
+			NegativeKind::TwosCompliment => "",
+			NegativeKind::OnesCompliment => " ones-compliment",
+			NegativeKind::SignMagnitude => " sign-magnitude",
+		});
+		if header.pad_bits {
+			line.push_str(" padbits");
+		}
+
+		Some(line)
+	}
+
+	fn byteorder(byteorder: ByteOrder) -> &'static str {
+		match byteorder {
+			ByteOrder::BigEndian => "big-endian",
+			ByteOrder::LittleEndian => "little-endian",
+		}
+	}
+
+	fn render(&self, lines: &[Line]) -> String {
+		let mut formatted = String::new();
+
+		let mut index = 0;
+		while index < lines.len() {
+			// Trailing comments line up across lines next to each other at
+			// the same depth
+			let run = lines[index..]
+				.iter()
+				.take_while(|line| {
+					Self::alignable(line)
+						&& line.depth == lines[index].depth
+						&& (!line.spaced || std::ptr::eq(*line, &lines[index]))
+				})
+				.count()
+				.max(1);
+			let width = lines[index..index + run]
+				.iter()
+				.map(|line| line.code.join(" ").chars().count())
+				.max()
+				.unwrap_or(0);
+
+			for line in &lines[index..index + run] {
+				if line.spaced && !formatted.is_empty() {
+					formatted.push('\n');
+				}
+
+				let code = line.code.join(" ");
+				formatted.push_str(&"\t".repeat(line.depth));
+				formatted.push_str(&code);
+				match (&line.comment, code.is_empty()) {
+					(Some(comment), true) => formatted.push_str(comment),
+					(Some(comment), false) => {
+						let padding = width.saturating_sub(code.chars().count());
+						formatted.push_str(&" ".repeat(padding + 1));
+						formatted.push_str(comment);
+					}
+					(None, _) => (),
+				}
+				formatted.push('\n');
+			}
+
+			index += run;
+		}
+
+		formatted
+	}
+
+	/// Whether the line has code and a comment after it, and the code fits
+	/// on one line
+	fn alignable(line: &Line) -> bool {
+		!line.code.is_empty()
+			&& line.comment.is_some()
+			&& !line.code.iter().any(|code| code.contains('\n'))
+	}
+
+	/// Whether two files lower the same way. Comments have to be the same,
+	/// though they may have moved, and octets may be grouped differently
+	/// unless a group is repeated.
+	fn same(a: &[Node], b: &[Node]) -> bool {
+		let (mut a_comments, mut b_comments) = (vec![], vec![]);
+		let a = Self::plain(a, &mut a_comments);
+		let b = Self::plain(b, &mut b_comments);
+
+		a == b && a_comments == b_comments
+	}
+
+	/// Nodes without spans or comments and with octets that aren't repeated
+	/// merged together. The comments are put in `comments`.
+	fn plain(nodes: &[Node], comments: &mut Vec<String>) -> Vec<Node> {
+		let mut plain: Vec<Node> = vec![];
+		let mut merged = false;
+
+		for (index, node) in nodes.iter().enumerate() {
+			let mut kind = node.kind.clone();
+			match &mut kind {
+				NodeKind::Comment(comment) => {
+					comments.push(comment.trim_end().to_string());
+					continue;
+				}
+				NodeKind::Block { nodes, close, .. } => {
+					*nodes = Self::plain(nodes, comments);
+					*close = Span::default();
+				}
+				NodeKind::Macro { body, .. } => body.span = Span::default(),
+				NodeKind::If {
+					then, otherwise, ..
+				} => {
+					then.span = Span::default();
+					if let Some(otherwise) = otherwise {
+						otherwise.span = Span::default();
+					}
+				}
+				_ => (),
+			}
+
+			let repeated = nodes[index + 1..]
+				.iter()
+				.find(|node| !matches!(node.kind, NodeKind::Comment(_)))
+				.is_some_and(|node| matches!(node.kind, NodeKind::Repeat(_)));
+
+			if let NodeKind::Octets(octets) = &kind {
+				if let Some(Node {
+					kind: NodeKind::Octets(last),
+					..
+				}) = plain.last_mut()
+				{
+					if merged && !repeated {
+						last.extend_from_slice(octets);
+						continue;
+					}
+				}
+			}
+			merged = matches!(kind, NodeKind::Octets(_)) && !repeated;
+
+			plain.push(Node {
+				kind,
+				span: Span::default(),
+			});
+		}
+
+		plain
+	}
+}
@@ -0,0 +1,530 @@
+use crate::macros::{self, Macro};
+use crate::source::Cursor;
+use crate::{Error, ErrorKind, Hext, Span};
+use std::collections::VecDeque;
+
+/// Directives with a body that's closed by `!end`
+pub(crate) const OPENERS: [&str; 2] = ["macro", "if"];
+
+/// Splits hext source into [Token]s. It stops after the first error.
+///
+/// Only the text is looked at, so a token may still be wrong in a way that
+/// [crate::Hext::lower] finds, like a width of 200 or a constant that isn't
+/// defined.
+pub struct Lexer<'a> {
+	chars: Cursor<'a>,
+	/// Tokens found along with the last one, like the comment after a
+	/// constant or those in the middle of a run of bits
+	pending: VecDeque<Token>,
+	failed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+	pub kind: TokenKind,
+	pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+	/// A `~` line without the `~`. The first is the header and any after it
+	/// change the byte order.
+	Header(String),
+	/// A comment without the `#`
+	Comment(String),
+	Octet(u8),
+	/// A run of bits like `.0110`
+	Bits(Vec<bool>),
+	/// What follows the `=` of an unsized decimal
+	Unsized(String),
+	/// A sized field like `u16be=4`, with what follows its letter
+	Field(FieldKind, String),
+	/// `{`
+	Open,
+	/// A block with its length in front, like `u16+2{`, with what's between
+	/// the `u` and the brace
+	LengthBlock(String),
+	/// `varint{`, with any adjustment that comes after `varint`
+	Varint(String),
+	Label(String),
+	/// A label that opens a block, like `@name{`
+	NamedBlock(String),
+	/// `}`
+	Close,
+	/// `* 3`, with the count
+	Repeat(String),
+	/// `$NAME = value`, without the `$` or a comment after it
+	Constant(String),
+	/// `!name` or `!name(arguments)`, which may be a macro invocation
+	Directive {
+		name: String,
+		arguments: Option<String>,
+	},
+	/// `!macro name(a, b)` and the body on the lines after, which isn't
+	/// lexed as it isn't hext until it's invoked. The span is the `!macro`
+	/// line and the `!end` that closes it comes right after the body.
+	Macro {
+		definition: String,
+		body: Body,
+	},
+	/// `!if(condition)` and its branches, which aren't lexed until the
+	/// condition picks one. The span is the `!if(condition)`. `!else` comes
+	/// right after `then` if there's an `otherwise`, and `!end` after the
+	/// last branch.
+	If {
+		condition: Option<String>,
+		then: Body,
+		otherwise: Option<Body>,
+	},
+	/// A string literal with its escapes worked out
+	Literal(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldKind {
+	Unsigned,
+	Signed,
+	Float,
+}
+
+/// Source that's kept as text, like the body of a macro
+#[derive(Clone, Debug, PartialEq)]
+pub struct Body {
+	pub text: String,
+	pub span: Span,
+}
+
+impl<'a> Lexer<'a> {
+	pub fn new(source: &'a str) -> Self {
+		Self::from_cursor(Cursor::new(source, 0))
+	}
+
+	pub(crate) fn from_cursor(chars: Cursor<'a>) -> Self {
+		Self {
+			chars,
+			pending: VecDeque::new(),
+			failed: false,
+		}
+	}
+
+	fn token(&mut self) -> Result<Option<Token>, Error> {
+		let chars = &mut self.chars;
+
+		loop {
+			let start = chars.mark();
+			let token = |kind, chars: &Cursor| {
+				Ok(Some(Token {
+					kind,
+					span: chars.span_from(start),
+				}))
+			};
+
+			// f is a hex digit, so the only way to tell f32=1 from the octet f3
+			// is that floats have an = before the next bit of whitespace
+			if chars.peek() == Some('f') && Self::is_float(chars.rest()) {
+				chars.next();
+				let float = Self::consume_token(chars);
+				return token(TokenKind::Field(FieldKind::Float, float), chars);
+			}
+
+			let kind = match chars.next() {
+				None => return Ok(None),
+				Some(c) if c.is_whitespace() => continue,
+
+				Some('#') => {
+					let (comment, _) = Self::consume_line(chars);
+					TokenKind::Comment(comment)
+				}
+				Some('~') => TokenKind::Header(Self::consume_line(chars).0),
+
+				Some(high) if high.is_ascii_hexdigit() => {
+					match chars.next_if(|c| c.is_ascii_hexdigit()) {
+						Some(low) => TokenKind::Octet(
+							(high.to_digit(16).unwrap() * 16 + low.to_digit(16).unwrap()) as u8,
+						),
+						None => {
+							return Err(Error::new(
+								ErrorKind::IncompleteOctet,
+								chars.span_from(start),
+							))
+						}
+					}
+				}
+
+				Some('{') => TokenKind::Open,
+				Some('}') => TokenKind::Close,
+				Some('*') => {
+					while chars.next_if(|c| matches!(c, ' ' | '\t')).is_some() {}
+					TokenKind::Repeat(Self::consume_token(chars))
+				}
+				Some('$') => {
+					let (line, _) = Self::consume_line(chars);
+
+					// Comments may follow the value
+					let definition = line.split('#').next().unwrap_or_default();
+					let end = start.start + 1 + definition.len();
+					let span = Span { end, ..start };
+
+					if let Some(comment) = line.get(definition.len() + 1..) {
+						self.pending.push_back(Token {
+							kind: TokenKind::Comment(comment.to_string()),
+							span: Span {
+								column: start.column + 1 + definition.chars().count(),
+								start: end,
+								..chars.span_from(start)
+							},
+						});
+					}
+
+					return Ok(Some(Token {
+						kind: TokenKind::Constant(definition.to_string()),
+						span,
+					}));
+				}
+				Some('v') => {
+					let spec = Self::consume_token(chars);
+					let opens_block = chars.next_if(|c| c == '{').is_some();
+
+					match spec.strip_prefix("arint").filter(|_| opens_block) {
+						Some(adjust) => TokenKind::Varint(adjust.to_string()),
+						None => {
+							return Err(Error::new(
+								ErrorKind::InvalidBlock(format!("v{}", spec)),
+								chars.span_from(start),
+							))
+						}
+					}
+				}
+				Some('=') => TokenKind::Unsized(Self::consume_token(chars)),
+				Some('@') => {
+					let name = Self::consume_token(chars);
+					match chars.next_if(|c| c == '{') {
+						Some(_) => TokenKind::NamedBlock(name),
+						None => TokenKind::Label(name),
+					}
+				}
+				Some('!') => return self.directive(start).map(Some),
+				Some('i') => TokenKind::Field(FieldKind::Signed, Self::consume_token(chars)),
+				Some('u') => {
+					let unsigned = Self::consume_token(chars);
+					if !unsigned.contains('=') && chars.next_if(|c| c == '{').is_some() {
+						TokenKind::LengthBlock(unsigned)
+					} else {
+						TokenKind::Field(FieldKind::Unsigned, unsigned)
+					}
+				}
+				Some('"') => TokenKind::Literal(Self::literal(chars, start)?),
+				Some('.') => TokenKind::Bits(Self::bits(chars, start, &mut self.pending)?),
+
+				Some(c) => {
+					return Err(Error::new(
+						ErrorKind::InvalidCharacter(c),
+						chars.span_from(start),
+					))
+				}
+			};
+
+			return token(kind, chars);
+		}
+	}
+
+	/// Everything after the `"` of a string literal, up to and including the
+	/// one that closes it
+	fn literal(chars: &mut Cursor, start: Span) -> Result<Vec<u8>, Error> {
+		let mut literal = vec![];
+
+		loop {
+			let escape_start = chars.mark();
+
+			match chars.next() {
+				Some('\"') => return Ok(literal),
+				Some('\\') => match chars.next() {
+					Some(c) => match Self::escape(c) {
+						Some(c) => literal.push(c as u8),
+						None => {
+							return Err(Error::new(
+								ErrorKind::InvalidEscape(c),
+								chars.span_from(escape_start),
+							))
+						}
+					},
+					None => {
+						return Err(Error::new(
+							ErrorKind::UnclosedStringLiteral,
+							chars.span_from(start),
+						))
+					}
+				},
+				Some('\n') | None => {
+					return Err(Error::new(
+						ErrorKind::UnclosedStringLiteral,
+						Span {
+							end: escape_start.start,
+							..start
+						},
+					))
+				}
+				Some(c) => {
+					let mut encode = vec![0; c.len_utf8()];
+					c.encode_utf8(&mut encode);
+					literal.extend_from_slice(&encode)
+				}
+			}
+		}
+	}
+
+	/// Everything after the `.` of a run of bits. A comment may come in the
+	/// middle of one, in which case the run goes on at the start of the next
+	/// line and the comment is put in `comments`.
+	fn bits(
+		chars: &mut Cursor,
+		start: Span,
+		comments: &mut VecDeque<Token>,
+	) -> Result<Vec<bool>, Error> {
+		if !matches!(chars.peek(), Some('0') | Some('1')) {
+			return Err(Error::new(
+				ErrorKind::GarbageCharacterInBitstream,
+				chars.span_from(start),
+			));
+		}
+
+		let mut run = vec![];
+		loop {
+			match chars.peek() {
+				Some('0') => run.push(false),
+				Some('1') => run.push(true),
+				Some('#') => {
+					let comment_start = chars.mark();
+					chars.next();
+					let (comment, _) = Self::consume_line(chars);
+					comments.push_back(Token {
+						kind: TokenKind::Comment(comment),
+						span: chars.span_from(comment_start),
+					});
+					chars.next();
+					continue;
+				}
+				_ => return Ok(run),
+			}
+			chars.next();
+		}
+	}
+
+	/// Everything after the `!` of a directive
+	fn directive(&mut self, start: Span) -> Result<Token, Error> {
+		let chars = &mut self.chars;
+
+		let mut name = String::new();
+		while let Some(c) = chars.next_if(macros::is_name_character) {
+			name.push(c);
+		}
+
+		if name == "macro" {
+			return Self::macro_definition(chars, start);
+		}
+
+		let arguments =
+			match chars.next_if(|c| c == '(') {
+				Some(_) => Some(Self::consume_arguments(chars).ok_or_else(|| {
+					Error::new(ErrorKind::UnclosedDirective, chars.span_from(start))
+				})?),
+				None => None,
+			};
+		let span = chars.span_from(start);
+
+		if name == "if" {
+			let unclosed = || Error::new(ErrorKind::UnclosedIf, span);
+			let (then, closer, _) =
+				Self::consume_body(chars, &["else", "end"]).ok_or_else(unclosed)?;
+			let otherwise = match closer {
+				"else" => match Self::consume_body(chars, &["else", "end"]).ok_or_else(unclosed)? {
+					(_, "else", span) => return Err(Error::new(ErrorKind::UnmatchedElse, span)),
+					(otherwise, _, _) => Some(otherwise),
+				},
+				_ => None,
+			};
+
+			return Ok(Token {
+				kind: TokenKind::If {
+					condition: arguments,
+					then,
+					otherwise,
+				},
+				span,
+			});
+		}
+
+		Ok(Token {
+			kind: TokenKind::Directive { name, arguments },
+			span,
+		})
+	}
+
+	/// The rest of a `!macro` line and the body on the lines after, up to the
+	/// `!end` that closes it
+	fn macro_definition(chars: &mut Cursor, start: Span) -> Result<Token, Error> {
+		let (line, line_span) = Self::consume_line(chars);
+		let span = Span {
+			start: start.start,
+			column: start.column,
+			..line_span
+		};
+
+		// Comments may follow the parameters
+		let definition = line.split('#').next().unwrap_or_default().trim();
+		let invalid = || Error::new(ErrorKind::InvalidMacro(definition.to_string()), span);
+
+		chars.next();
+		let name = Macro::parse(definition, chars.mark())
+			.ok_or_else(invalid)?
+			.name;
+
+		let (body, _, _) = Self::consume_body(chars, &["end"])
+			.ok_or_else(|| Error::new(ErrorKind::UnclosedMacro(name), span))?;
+
+		Ok(Token {
+			kind: TokenKind::Macro {
+				definition: definition.to_string(),
+				body,
+			},
+			span,
+		})
+	}
+
+	fn escape(c: char) -> Option<char> {
+		match c {
+			'\"' => Some('\"'),
+			'\\' => Some('\\'),
+			'n' => Some('\n'),
+			'r' => Some('\r'),
+			't' => Some('\t'),
+			_ => None,
+		}
+	}
+
+	/// Whether `rest`, which starts with an f, is a float like `f32=1.5` or
+	/// `f64be=2` rather than octets like `ff=1`
+	fn is_float(rest: &str) -> bool {
+		// Only the token matters, and looking past it would make lexing a file
+		// full of ff octets quadratic
+		let token = rest
+			.split(|c: char| c.is_whitespace() || c == '{' || c == '#')
+			.next()
+			.unwrap_or_default();
+		let Some((width, _)) = token.split_once('=') else {
+			return false;
+		};
+		let (width, _) = Hext::split_byteorder(&width[1..]);
+
+		matches!(width, "16" | "32" | "64")
+	}
+
+	/// Consume up to, but not including, the next newline. Returns the line
+	/// along with its span.
+	fn consume_line(chars: &mut Cursor) -> (String, Span) {
+		let start = chars.mark();
+		let mut line = String::new();
+		while let Some(c) = chars.next_if(|c| c != '\n') {
+			line.push(c);
+		}
+
+		(line, chars.span_from(start))
+	}
+
+	/// Consume up to whitespace or a brace, as tokens may open and close
+	/// blocks. Inside parentheses neither ends the token, so an expression
+	/// like `(A + 4)` can be spaced out.
+	fn consume_token(chars: &mut Cursor) -> String {
+		let mut string = String::new();
+		let mut depth = 0usize;
+
+		while let Some(c) =
+			chars.next_if(|c| depth > 0 || !(c.is_whitespace() || c == '{' || c == '}'))
+		{
+			match c {
+				'(' => depth += 1,
+				')' => depth = depth.saturating_sub(1),
+				_ => (),
+			}
+
+			string.push(c);
+		}
+
+		string
+	}
+
+	/// Consume everything up to the first of `closers` that isn't in a body of
+	/// its own, along with the closer. Returns what came before, which closer
+	/// it was, and its span. None if the source ends first.
+	fn consume_body<'c>(chars: &mut Cursor, closers: &[&'c str]) -> Option<(Body, &'c str, Span)> {
+		let (length, closer) = macros::find_closer(chars.rest(), &OPENERS, closers)?;
+
+		let start = chars.mark();
+		while chars.mark().start < start.start + length {
+			chars.next();
+		}
+		let span = chars.span_from(start);
+		let body = Body {
+			text: chars.text(span).to_string(),
+			span,
+		};
+
+		// The ! and the name
+		let closer_start = chars.mark();
+		for _ in 0..=closer.len() {
+			chars.next();
+		}
+
+		Some((body, closer, chars.span_from(closer_start)))
+	}
+
+	/// Consume the arguments of a directive up to the parenthesis that closes
+	/// them, which is consumed but not returned. None if the line ends first.
+	fn consume_arguments(chars: &mut Cursor) -> Option<String> {
+		let mut arguments = String::new();
+		let mut depth = 0;
+		let mut quoted = false;
+
+		loop {
+			let c = chars.next_if(|c| c != '\n')?;
+			match c {
+				// Parentheses in a string literal don't count
+				'"' => quoted = !quoted,
+				'\\' if quoted => {
+					arguments.push(c);
+					arguments.push(chars.next_if(|c| c != '\n')?);
+					continue;
+				}
+				_ if quoted => (),
+				')' if depth == 0 => return Some(arguments),
+				')' => depth -= 1,
+				'(' => depth += 1,
+				_ => (),
+			}
+
+			arguments.push(c);
+		}
+	}
+}
+
+impl Iterator for Lexer<'_> {
+	type Item = Result<Token, Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.failed {
+			return None;
+		}
+
+		if let Some(token) = self.pending.pop_front() {
+			return Some(Ok(token));
+		}
+
+		match self.token() {
+			Ok(token) => token.map(Ok),
+			Err(error) => {
+				self.failed = true;
+				Some(Err(error))
+			}
+		}
+	}
+}
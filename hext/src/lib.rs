@@ -1,658 +1,5049 @@
+mod ast;
+mod checksum;
+mod decompile;
+mod digest;
 mod error;
+mod expr;
+mod formatter;
+mod lexer;
+mod macros;
+mod resolve;
+mod schema;
+mod source;
+mod sourcemap;
 
-pub use crate::error::Error;
+pub use crate::ast::{Ast, Node, NodeKind, Opener};
+pub use crate::decompile::Decompiler;
+pub use crate::error::{Error, ErrorKind, Include, InvalidHeaderKind};
+pub use crate::formatter::Formatter;
+pub use crate::lexer::{Body, FieldKind, Lexer, Token, TokenKind};
+pub use crate::resolve::{FileResolver, Resolver};
+pub use crate::schema::Format;
+pub use crate::source::Span;
+pub use crate::sourcemap::{Mapping, SourceMap};
 use bitvec::prelude::*;
-use error::InvalidHeaderKind;
-use std::iter::Peekable;
-use std::num::ParseIntError;
-use std::str::Chars;
-
-#[derive(Debug, PartialEq)]
-struct Header {
-	bitorder: BitOrder,
-	byteorder: ByteOrder,
-	negativekind: NegativeKind,
-	pad_bits: bool,
+use checksum::Checksum;
+#[cfg(feature = "digest")]
+use digest::Digest;
+use expr::Expression;
+use macros::{Expansion, Macro};
+use source::Cursor;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
+
+/// The `~` line every file starts with, as in `~little-endian msb0`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+	pub bitorder: BitOrder,
+	pub byteorder: ByteOrder,
+	pub negativekind: NegativeKind,
+	/// Pad bits that don't make a whole octet with zeroes instead of failing
+	pub pad_bits: bool,
 }
 
-#[derive(Debug, PartialEq)]
-enum BitOrder {
+#[derive(Clone, Debug, PartialEq)]
+pub enum BitOrder {
 	Msb0,
 	Lsb0,
 }
 
-#[derive(Debug, PartialEq)]
-enum ByteOrder {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteOrder {
 	LittleEndian,
 	BigEndian,
 }
 
-#[derive(Debug, PartialEq)]
-enum NegativeKind {
+#[derive(Clone, Debug, PartialEq)]
+pub enum NegativeKind {
 	TwosCompliment,
 	OnesCompliment,
 	SignMagnitude,
 }
 
+/// The directives that aren't checksums or digests. Macros can't use these
+/// names.
+const DIRECTIVES: [&str; 8] = [
+	"include", "embed", "align", "pad", "macro", "if", "else", "end",
+];
+
 pub struct Hext {
 	parsed: Vec<u8>,
+	/// Bits that haven't made a whole octet yet, in the order they were written.
+	/// Bit runs and sized decimals both land here.
+	bits: BitVec<u8, Msb0>,
+	/// Where the bits in `bits` came from, in case they don't end up aligned
+	bits_span: Span,
+	/// Byte offsets of labels and where they were defined
+	labels: HashMap<String, (usize, Span)>,
+	/// Label names in the order they were defined
+	label_order: Vec<String>,
+	/// Blocks that have been opened but not closed, innermost last
+	blocks: Vec<Block>,
+	/// How many length blocks there have been, which names their hidden labels
+	block_count: usize,
+	/// Fields that refer to labels or checksum a region, to be filled in once
+	/// we know where every label is. Their offsets are in bits from the start
+	/// of the output.
+	fixups: Vec<Fixup>,
+	/// Fixups for fields that are still in `bits`. Their offsets are from the
+	/// start of `bits` until they're flushed, as padding may move them.
+	pending_fixups: Vec<Fixup>,
+	/// Values of constants like `$WIDTH`, by name
+	constants: HashMap<String, i128>,
+	/// Constants given to [Hext::define], which win over any definition of
+	/// the same name in the source
+	defines: HashSet<String>,
+	/// Macros made with `!macro`, by name
+	macros: HashMap<String, Rc<Macro>>,
+	/// Finds the files named by `!include` and `!embed`
+	resolver: Box<dyn Resolver>,
+	/// Every source we've parsed, indexed by the `source` of their spans. The
+	/// first is the one given to [Hext::parse].
+	sources: Vec<SourceFile>,
+	/// The bits of the output each thing we lowered wrote, when we're making a
+	/// source map. Like fixups, those still in `bits` move if it's padded.
+	mappings: Option<Vec<(Range<usize>, Span)>>,
+	/// How many mappings there were and the bit we were at when each thing
+	/// still being lowered started, innermost last. These move with padding
+	/// too.
+	map_marks: Vec<(usize, usize)>,
+}
+
+struct SourceFile {
+	name: Option<String>,
+	/// The directive that included this file. None for the first source.
+	included_from: Option<Span>,
+	/// Set if this is the body of a macro rather than a file, in which case
+	/// `included_from` is the invocation and `name` is where it was defined
+	expansion: Option<Expansion>,
+}
+
+/// A field whose value can't be known until the whole file has been parsed
+struct Fixup {
+	bit_offset: usize,
+	width: u32,
+	signed: bool,
+	byteorder: ByteOrder,
+	value: FixupValue,
+	/// The text of the field, for error messages
+	text: String,
+	span: Span,
+}
+
+enum FixupValue {
+	/// A sized decimal whose value is an expression involving labels
+	Expression(Expression),
+	/// The length of a block, which is the difference of its hidden labels
+	BlockLength(Expression),
+	/// A checksum or digest of the octets from the label `start` up to the
+	/// label `end`
+	Region {
+		sum: Sum,
+		start: String,
+		end: String,
+	},
+}
+
+#[derive(Clone, Copy)]
+enum Sum {
+	Checksum(Checksum),
+	#[cfg(feature = "digest")]
+	Digest(Digest),
+}
+
+struct Block {
+	kind: BlockKind,
+	/// The token that opened the block
+	span: Span,
+}
+
+enum BlockKind {
+	/// Only groups what's inside
+	Plain,
+	/// Started with @name{, defines name.end when it closes
+	Named(String),
+	/// A fixed width length comes first. The number names the hidden labels
+	/// at the start and end, which the length's fixup refers to.
+	Length(usize),
+	/// A LEB128 length comes first, which is inserted once the block closes.
+	/// `labels` and `fixups` are how many there were when it opened, as any
+	/// after that will have to be moved along.
+	Varint {
+		start: usize,
+		adjust: i128,
+		labels: usize,
+		fixups: usize,
+		mappings: usize,
+	},
+}
+
+impl Default for Hext {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl Hext {
 	pub fn new() -> Self {
-		Self { parsed: vec![] }
+		Self {
+			parsed: vec![],
+			bits: BitVec::new(),
+			bits_span: Span::default(),
+			labels: HashMap::new(),
+			label_order: vec![],
+			blocks: vec![],
+			block_count: 0,
+			fixups: vec![],
+			pending_fixups: vec![],
+			constants: HashMap::new(),
+			defines: HashSet::new(),
+			macros: HashMap::new(),
+			resolver: Box::new(FileResolver),
+			sources: vec![SourceFile {
+				name: None,
+				included_from: None,
+				expansion: None,
+			}],
+			mappings: None,
+			map_marks: vec![],
+		}
+	}
+
+	/// Name the source given to [Hext::parse], usually after the file it came
+	/// from. Errors carry the name and files it includes are found relative
+	/// to it.
+	pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+		self.sources[0].name = Some(name.into());
+		self
+	}
+
+	/// Define a constant before parsing, as if the source started with
+	/// `$NAME = value`. It wins over a definition in the source, which can
+	/// then be a default. `!if` sections can be picked with these, as in
+	/// `!if(defined(EXTENSION))` or `!if(VERSION >= 2)`.
+	pub fn define<S: Into<String>>(mut self, name: S, value: i128) -> Self {
+		let name = name.into();
+		self.constants.insert(name.clone(), value);
+		self.defines.insert(name);
+		self
+	}
+
+	/// Find included files with `resolver` instead of reading them from the
+	/// filesystem with a [FileResolver]
+	pub fn resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+		self.resolver = Box::new(resolver);
+		self
 	}
 
 	pub fn parse<S: AsRef<str>>(mut self, raw: S) -> Result<Vec<u8>, Error> {
-		let mut chars = raw.as_ref().chars().peekable();
+		let (ast, error) = Ast::parse_partial(raw.as_ref());
+		match self.lower_main(&ast, error) {
+			Ok(()) => Ok(self.parsed),
+			Err(error) => Err(self.locate(error)),
+		}
+	}
 
-		// Clear through any leading comments or blank lines
-		Self::skip_nondata(&mut chars);
+	/// Turn a file that's already been parsed into bytes
+	pub fn lower(mut self, ast: &Ast) -> Result<Vec<u8>, Error> {
+		match self.lower_main(ast, None) {
+			Ok(()) => Ok(self.parsed),
+			Err(error) => Err(self.locate(error)),
+		}
+	}
 
-		let header: Header;
-		loop {
-			match chars.next() {
-				Some('~') => {
-					header = Self::parse_header(Self::consume_line(&mut chars))?;
-					break;
+	/// Parse like [Hext::parse], along with a map of which part of the source
+	/// each run of bytes came from
+	pub fn parse_mapped<S: AsRef<str>>(self, raw: S) -> Result<(Vec<u8>, SourceMap), Error> {
+		let (ast, error) = Ast::parse_partial(raw.as_ref());
+		self.mapped(&ast, error)
+	}
+
+	/// Lower like [Hext::lower], along with a source map
+	pub fn lower_mapped(self, ast: &Ast) -> Result<(Vec<u8>, SourceMap), Error> {
+		self.mapped(ast, None)
+	}
+
+	/// Lower with a source map. `error` is one found parsing the AST, which is
+	/// only given if nothing in the AST goes wrong first.
+	fn mapped(mut self, ast: &Ast, error: Option<Error>) -> Result<(Vec<u8>, SourceMap), Error> {
+		self.mappings = Some(vec![]);
+		if let Err(error) = self.lower_main(ast, error) {
+			return Err(self.locate(error));
+		}
+
+		let mut mappings: Vec<Mapping> = self
+			.mappings
+			.take()
+			.unwrap_or_default()
+			.into_iter()
+			.map(|(bits, span)| {
+				let span = self.original(span);
+				Mapping {
+					bytes: bits.start / 8..bits.end.div_ceil(8),
+					file: self.sources[span.source].name.clone(),
+					span,
 				}
-				Some(_) => return Err(Error::NoHeader),
-				None => return Ok(self.parsed), //todo: is this an error?
-			}
+			})
+			.collect();
+		// Varint lengths are mapped after what's in their block
+		mappings.sort_by_key(|mapping| mapping.bytes.start);
+
+		Ok((self.parsed, SourceMap { mappings }))
+	}
+
+	/// Fill in which file an error is in and the includes and invocations
+	/// that led there
+	fn locate(&self, error: Error) -> Error {
+		let mut includes = vec![];
+		let mut source = error.span().source;
+		while let Some(directive) = self.sources[source].included_from {
+			let span = self.original(directive);
+			includes.push(Include {
+				file: self.sources[span.source].name.clone(),
+				span,
+				macro_name: self.sources[source]
+					.expansion
+					.as_ref()
+					.map(|expansion| expansion.definition.name.clone()),
+			});
+			source = directive.source;
 		}
+		includes.reverse();
 
-		let mut bits: BitVec<u8, Msb0> = BitVec::new();
-		let mut state = State::ReadingHex;
+		let span = self.original(error.span());
+		error.locate(span, self.sources[span.source].name.clone(), includes)
+	}
 
-		loop {
-			match state {
-				State::ReadingHex => match chars.next_if(|&c| c != '.') {
-					Some('#') => Self::skip_line(&mut chars),
-					Some(c) if c.is_whitespace() => continue,
-
-					Some(high) if high.is_ascii_hexdigit() => {
-						match chars.next_if(|&c| c.is_ascii_hexdigit()) {
-							Some(low) => self.parsed.push(
-								((high.to_digit(16).unwrap() * 16) + low.to_digit(16).unwrap())
-									as u8,
-							),
-							None => return Err(Error::IncompleteOctet),
-						}
-					}
+	/// Follow a span out of any macro expansions to where it was written in
+	/// the body of the macro
+	fn original(&self, mut span: Span) -> Span {
+		while let Some(expansion) = &self.sources[span.source].expansion {
+			span = expansion.original(span);
+		}
 
-					Some('=') => state = State::ReadingUnsizedDecimal,
-					Some('i') => state = State::ReadingSignedDecimal,
-					Some('u') => state = State::ReadingUnsignedDecimal,
-					Some('\"') => state = State::ReadingLiteral,
-					Some(c) => return Err(Error::InvalidCharacter(c)),
+		span
+	}
 
-					None => match chars.peek() {
-						Some('.') => state = State::ReadingBinary,
-						Some(_) => unreachable!(),
-						None => return Ok(self.parsed),
-					},
-				},
+	/// `error` is one found parsing the AST, which only counts if lowering
+	/// what was parsed before it goes well
+	fn lower_main(&mut self, ast: &Ast, error: Option<Error>) -> Result<(), Error> {
+		// Comments may come before the header
+		let Some(first) = ast
+			.nodes
+			.iter()
+			.position(|node| !matches!(node.kind, NodeKind::Comment(_)))
+		else {
+			return error.map_or(Ok(()), Err); //todo: is this an error?
+		};
+		let header = match &ast.nodes[first].kind {
+			NodeKind::Header(header) => header.clone(),
+			_ => return Err(Error::new(ErrorKind::NoHeader, ast.nodes[first].span)),
+		};
 
-				State::ReadingUnsizedDecimal => {
-					let decimal = Self::consume_until_whitespace(&mut chars);
-					state = State::ReadingHex;
-
-					let is_signed = if let Some(sign) = decimal.chars().next() {
-						sign == '-' || sign == '+'
-					} else {
-						// it was a lone =. Send the maybe-decimal string even
-						// though we know it's empty
-						return Err(Error::InvalidDecimal(decimal));
-					};
-
-					let mut bytes = if is_signed {
-						Self::signed_smallest_le_bytes(&decimal)
-					} else {
-						Self::unsigned_smallest_le_bytes(&decimal)
-					}
-					.map_err(|_e| Error::InvalidDecimal(decimal))?;
+		self.lower_nodes(&ast.nodes[first + 1..], &mut header.clone())?;
+		if let Some(error) = error {
+			return Err(error);
+		}
+		self.flush_bits(&header)?;
+		self.resolve_fixups(&header)
+	}
 
-					if header.byteorder == ByteOrder::BigEndian {
-						bytes.reverse();
-					}
+	/// Write out everything in `nodes`. `header` is ours to change as byte
+	/// order changes come along.
+	fn lower_nodes(&mut self, nodes: &[Node], header: &mut Header) -> Result<(), Error> {
+		let mut index = 0;
+		while let Some(node) = nodes.get(index) {
+			index += 1;
+
+			let repeatable = matches!(
+				node.kind,
+				NodeKind::Octets(_)
+					| NodeKind::Bits(_)
+					| NodeKind::Unsized(_)
+					| NodeKind::Field { .. }
+					| NodeKind::Literal(_)
+					| NodeKind::Block { .. }
+					| NodeKind::Directive { .. }
+			);
+			if !repeatable {
+				self.lower_node(node, header)?;
+				continue;
+			}
 
-					self.parsed.extend_from_slice(&bytes);
+			// A * after it, maybe with comments in between, says how many
+			// times to write it in all. That may be none at all.
+			let next = nodes[index..]
+				.iter()
+				.position(|next| !matches!(next.kind, NodeKind::Comment(_)));
+			match next.map(|offset| (offset, &nodes[index + offset])) {
+				Some((
+					offset,
+					Node {
+						kind: NodeKind::Repeat(count),
+						span,
+					},
+				)) => {
+					for _ in 0..self.repeat_count(count, *span)? {
+						self.lower_node(node, header)?;
+					}
+					index += offset + 1;
 				}
+				_ => self.lower_node(node, header)?,
+			}
+		}
 
-				State::ReadingSignedDecimal => {
-					let signed_decimal_string = Self::consume_until_whitespace(&mut chars);
-					state = State::ReadingHex;
+		Ok(())
+	}
 
-					let splits = signed_decimal_string.split_once('=');
-					match splits {
-						Some((bitness, value)) => {
-							let mut bytes = Self::signed_le_bytes(bitness, value)?;
+	fn lower_node(&mut self, node: &Node, header: &mut Header) -> Result<(), Error> {
+		let span = node.span;
 
-							if header.byteorder == ByteOrder::BigEndian {
-								bytes.reverse();
-							}
+		// Whole octets are written out before anything else comes along, so
+		// padding only ever goes on the bits that come after them
+		if !self.bits.is_empty() && self.bits.len().is_multiple_of(8) {
+			self.flush_bits(header)?;
+		}
+		self.map_mark();
 
-							self.parsed.extend_from_slice(&bytes);
-						}
-						None => return Err(Error::InvalidSignedDecimal(signed_decimal_string)),
+		match &node.kind {
+			NodeKind::Header(new) => *header = new.clone(),
+			NodeKind::ByteOrder(byteorder) => header.byteorder = *byteorder,
+			NodeKind::Comment(_) => (),
+			NodeKind::Octets(octets) => {
+				self.flush_bits(header)?;
+				self.parsed.extend_from_slice(octets);
+			}
+			NodeKind::Bits(bits) => self.push_bits(bits, span),
+			NodeKind::Unsized(decimal) => self.push_unsized(decimal, span, header)?,
+			NodeKind::Field {
+				kind,
+				width,
+				byteorder,
+				value,
+			} => {
+				let byteorder = byteorder.unwrap_or(header.byteorder);
+				match kind {
+					FieldKind::Unsigned => {
+						self.push_value(width, value, false, byteorder, span, header)?
+					}
+					FieldKind::Signed => {
+						self.push_value(width, value, true, byteorder, span, header)?
+					}
+					FieldKind::Float => {
+						let (raw, width) = Self::float_field(width.as_str(), value.as_str())
+							.map_err(|kind| Error::new(kind, span))?;
+						self.push_field(raw, width, byteorder, span, header);
 					}
 				}
+			}
+			NodeKind::Literal(literal) => {
+				self.flush_bits(header)?;
+				self.parsed.extend_from_slice(literal);
+			}
+			NodeKind::Label(name) => self.define_label(name.clone(), span)?,
+			NodeKind::Block {
+				opener,
+				nodes,
+				close,
+			} => {
+				self.map_mark();
+				self.open_block(opener, span, header)?;
+				self.map(span);
 
-				State::ReadingUnsignedDecimal => {
-					let signed_decimal_string = Self::consume_until_whitespace(&mut chars);
-					state = State::ReadingHex;
+				self.lower_nodes(nodes, header)?;
+				self.close_block(*close, header)?;
+			}
+			// Repeats are taken care of with what they repeat
+			NodeKind::Repeat(_) => return Err(Error::new(ErrorKind::NothingToRepeat, span)),
+			NodeKind::Constant { name, value } => self.define_constant(name, value, span)?,
+			NodeKind::Directive { name, arguments } => {
+				self.directive(name, arguments.as_deref(), span, header)?
+			}
+			NodeKind::Macro { definition, body } => self.define_macro(definition, body, span)?,
+			NodeKind::If {
+				condition,
+				then,
+				otherwise,
+			} => self.conditional(condition.as_deref(), then, otherwise.as_ref(), span, header)?,
+		}
 
-					let splits = signed_decimal_string.split_once('=');
-					match splits {
-						Some((bitness, value)) => {
-							let mut bytes = Self::unsigned_le_bytes(bitness, value)?;
+		self.map(span);
+		Ok(())
+	}
 
-							if header.byteorder == ByteOrder::BigEndian {
-								bytes.reverse();
-							}
+	/// Start mapping what's lowered until the next [Hext::map] to a span
+	fn map_mark(&mut self) {
+		let count = self.mappings.as_ref().map_or(0, Vec::len);
+		self.map_marks
+			.push((count, self.parsed.len() * 8 + self.bits.len()));
+	}
 
-							self.parsed.extend_from_slice(&bytes);
-						}
-						None => return Err(Error::InvalidDecimal(signed_decimal_string)),
-					}
+	/// Note that what's been written since the last mark came from `span`,
+	/// unless something inside it, like a node in an included file, already
+	/// said where its part came from
+	fn map(&mut self, span: Span) {
+		let Some((count, start)) = self.map_marks.pop() else {
+			return;
+		};
+
+		let end = self.parsed.len() * 8 + self.bits.len();
+		if let Some(mappings) = &mut self.mappings {
+			if mappings.len() == count && end > start {
+				mappings.push((start..end, span));
+			}
+		}
+	}
+
+	/// Lower something that's part of what we're already lowering, like an
+	/// include or a macro. It gets its own copy of the header and has to close
+	/// the blocks it opens.
+	fn lower_nested(&mut self, chars: Cursor, header: &Header) -> Result<(), Error> {
+		let (nodes, error) = Ast::nested_partial(chars);
+		self.lower_nodes(&nodes, &mut header.clone())?;
+		error.map_or(Ok(()), Err)
+	}
+
+	/// Push an unsized decimal, which takes as few octets as its value needs
+	fn push_unsized(&mut self, decimal: &str, span: Span, header: &Header) -> Result<(), Error> {
+		// A plain number keeps its sign, even on a zero, and is only signed if
+		// it has one. Anything else is worked out first.
+		let (is_signed, negative, magnitude) = match Self::split_sign(decimal) {
+			Some((negative, magnitude)) => (decimal.starts_with(['-', '+']), negative, magnitude),
+			None => match self.evaluate(decimal) {
+				Ok(value) => (value < 0, value < 0, value.unsigned_abs()),
+				// it may have been a lone =. Send the maybe-decimal string even
+				// though we know it's empty
+				Err(ErrorKind::InvalidExpression(_)) => {
+					return Err(Error::new(
+						ErrorKind::InvalidDecimal(decimal.to_string()),
+						span,
+					))
 				}
+				Err(kind) => return Err(Error::new(kind, span)),
+			},
+		};
 
-				State::ReadingLiteral => match chars.next() {
-					Some('\"') => state = State::ReadingHex,
-					Some('\\') => match chars.next() {
-						Some(c) => match Self::escape(c) {
-							Some(c) => self.parsed.push(c as u8),
-							None => return Err(Error::InvalidEscape(c)),
-						},
-						None => return Err(Error::UnclosedStringLiteral),
-					},
-					Some('\n') => return Err(Error::UnclosedStringLiteral),
-					Some(c) => {
-						let mut encode = vec![0; c.len_utf8()];
-						c.encode_utf8(&mut encode);
-						self.parsed.extend_from_slice(&encode)
-					}
-					None => return Err(Error::UnclosedStringLiteral),
-				},
+		let bytes = if is_signed {
+			Self::signed_smallest_le_bytes(negative, magnitude, &header.negativekind)
+		} else {
+			Some(Self::unsigned_smallest_le_bytes(magnitude))
+		};
+		let mut bytes = bytes
+			.ok_or_else(|| Error::new(ErrorKind::InvalidDecimal(decimal.to_string()), span))?;
 
-				State::ReadingBinary => match chars.next_if(|&c| c == '.') {
-					Some('.') => loop {
-						match chars
-							.next_if(|&c| c == '1' || c == '0' || c == '#' || c.is_whitespace())
-						{
-							Some('0') => bits.push(false),
-							Some('1') => bits.push(true),
-							Some('#') => Self::skip_line(&mut chars),
-							Some(c) if c.is_whitespace() => {
-								Self::skip_nondata(&mut chars);
-								break;
-							}
-							Some(_) => return Err(Error::GarbageCharacterInBitstream),
-							None => break,
-						}
-					},
-					Some(_) => unreachable!(),
-					None => {
-						if bits.len() % 8 != 0 {
-							if !header.pad_bits {
-								eprintln!("{}", bits.len());
-								return Err(Error::UnalignedBits);
-							} else {
-								while bits.len() % 8 != 0 {
-									bits.insert(0, false);
-								}
-							}
-						}
-
-						self.parsed.extend_from_slice(bits.as_raw_slice());
-						bits = BitVec::new();
-
-						state = State::ReadingHex;
-					}
-				},
+		if header.byteorder == ByteOrder::BigEndian {
+			bytes.reverse();
+		}
+
+		self.flush_bits(header)?;
+		self.parsed.extend_from_slice(&bytes);
+		Ok(())
+	}
+
+	/// How many times in all a repeat writes what it repeats
+	fn repeat_count(&self, count: &str, span: Span) -> Result<usize, Error> {
+		let invalid = || Error::new(ErrorKind::InvalidRepeat(count.to_string()), span);
+
+		match self.evaluate(count) {
+			Ok(count) => usize::try_from(count).map_err(|_| invalid()),
+			Err(ErrorKind::InvalidExpression(_)) => Err(invalid()),
+			Err(kind) => Err(Error::new(kind, span)),
+		}
+	}
+
+	fn push_bits(&mut self, bits: &[bool], span: Span) {
+		if self.bits.is_empty() {
+			self.bits_span = span;
+		} else {
+			self.bits_span.end = span.end;
+		}
+
+		self.bits.extend(bits);
+	}
+
+	/// Push the low `width` bits of `raw` into the bitstream
+	fn push_field(
+		&mut self,
+		raw: u128,
+		width: u32,
+		byteorder: ByteOrder,
+		span: Span,
+		header: &Header,
+	) {
+		let bits = Self::field_bits(raw, width, byteorder, &header.bitorder);
+		self.push_bits(&bits, span);
+	}
+
+	/// The bits of a field in the order they're written. Whole octets are laid
+	/// out in `byteorder` and any leftover bits are the most significant end of
+	/// the value. Each piece is then written in `bitorder`.
+	fn field_bits(raw: u128, width: u32, byteorder: ByteOrder, bitorder: &BitOrder) -> Vec<bool> {
+		let bytes = raw.to_le_bytes();
+		let whole = (width / 8) as usize;
+		let leftover = width % 8;
+
+		let mut pieces: Vec<(u8, u32)> = bytes[..whole].iter().map(|&b| (b, 8)).collect();
+		if leftover != 0 {
+			pieces.push((bytes[whole], leftover));
+		}
+
+		if byteorder == ByteOrder::BigEndian {
+			pieces.reverse();
+		}
+
+		let mut bits = Vec::with_capacity(width as usize);
+		for (piece, piece_width) in pieces {
+			let piece_bits = (0..piece_width).map(|i| piece >> i & 1 == 1);
+
+			match bitorder {
+				BitOrder::Msb0 => bits.extend(piece_bits.rev()),
+				BitOrder::Lsb0 => bits.extend(piece_bits),
 			}
 		}
+
+		bits
 	}
 
-	fn parse_header<S: AsRef<str>>(string: S) -> Result<Header, Error> {
-		let splits: Vec<&str> = string.as_ref().trim_end().split(' ').collect();
+	/// Push a sized field from its value, as in the `4*COUNT` of
+	/// `u32=4*COUNT`. Values that refer to labels get a placeholder which we
+	/// fill in with [Hext::resolve_fixups] once they've all been placed.
+	fn push_value(
+		&mut self,
+		bitness: &str,
+		value: &str,
+		signed: bool,
+		byteorder: ByteOrder,
+		span: Span,
+		header: &Header,
+	) -> Result<(), Error> {
+		let error = |kind| Error::new(kind, span);
 
-		let mut bitorder = None;
-		let mut byteorder = None;
-		let mut negativekind = None;
-		let mut pad_bits = false;
+		// Plain numbers keep their sign, which matters for a negative zero
+		if Self::split_sign(value).is_some() {
+			let (raw, width) = if signed {
+				Self::signed_field(bitness, value, &header.negativekind)
+			} else {
+				Self::unsigned_field(bitness, value)
+			}
+			.map_err(error)?;
 
-		for split in splits {
-			match split {
-				"msb0" => {
-					if bitorder.replace(BitOrder::Msb0).is_some() {
-						return Err(InvalidHeaderKind::TwoBitOrder.into());
+			self.push_field(raw, width, byteorder, span, header);
+			return Ok(());
+		}
+
+		let width = Self::parse_bitness(bitness).map_err(error)?;
+		let expression = self.expression(value).map_err(|kind| match kind {
+			// Without a label it was most likely meant to be a number
+			ErrorKind::InvalidExpression(_) if !value.contains('@') => error(if signed {
+				ErrorKind::InvalidSignedDecimal(value.to_string())
+			} else {
+				ErrorKind::InvalidUnsignedDecimal(value.to_string())
+			}),
+			kind => error(kind),
+		})?;
+
+		if expression.label().is_some() {
+			self.pending_fixups.push(Fixup {
+				bit_offset: self.bits.len(),
+				width,
+				signed,
+				byteorder,
+				value: FixupValue::Expression(expression),
+				text: value.to_string(),
+				span,
+			});
+			self.push_field(0, width, byteorder, span, header);
+
+			return Ok(());
+		}
+
+		let number = expression.evaluate(&|_| None).map_err(error)?;
+		let raw =
+			Self::encode_value(number, width, signed, &header.negativekind).ok_or_else(|| {
+				error(ErrorKind::DoesNotFit {
+					expression: value.to_string(),
+					value: number,
+					width,
+				})
+			})?;
+
+		self.push_field(raw, width, byteorder, span, header);
+		Ok(())
+	}
+
+	/// Parse an expression, filling in the constants defined so far
+	fn expression(&self, text: &str) -> Result<Expression, ErrorKind> {
+		Expression::parse(text, |name| self.constants.get(name).copied())
+	}
+
+	/// Work out an expression that has to be known straight away, so it
+	/// can't refer to labels
+	fn evaluate(&self, text: &str) -> Result<i128, ErrorKind> {
+		let expression = self.expression(text)?;
+		if let Some(label) = expression.label() {
+			return Err(ErrorKind::LabelNotAllowed(label.to_string()));
+		}
+
+		expression.evaluate(&|_| None)
+	}
+
+	/// Define a constant from its name, without the $, and its value
+	fn define_constant(&mut self, name: &str, value: &str, span: Span) -> Result<(), Error> {
+		if !Self::is_constant_name(name) {
+			return Err(Error::new(
+				ErrorKind::InvalidConstant(name.to_string()),
+				span,
+			));
+		}
+
+		// One given to Hext::define wins, so this is only a default
+		if self.defines.contains(name) {
+			return Ok(());
+		}
+
+		if self.constants.contains_key(name) {
+			return Err(Error::new(
+				ErrorKind::DuplicateConstant(name.to_string()),
+				span,
+			));
+		}
+
+		// Constants can be defined in terms of ones that came before
+		let value = self.evaluate(value).map_err(|kind| match kind {
+			ErrorKind::InvalidExpression(_) => {
+				Error::new(ErrorKind::InvalidConstant(value.to_string()), span)
+			}
+			kind => Error::new(kind, span),
+		})?;
+
+		self.constants.insert(name.to_string(), value);
+		Ok(())
+	}
+
+	/// Whether `name` can be used for a constant: letters, digits, and
+	/// underscores, not starting with a digit
+	pub fn is_constant_name(name: &str) -> bool {
+		name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+			&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+	}
+
+	/// Labels mark the offset of the next octet, so the bitstream has to be
+	/// aligned when they're defined.
+	fn define_label(&mut self, name: String, span: Span) -> Result<(), Error> {
+		if !Expression::is_label_name(&name) {
+			return Err(Error::new(ErrorKind::InvalidLabel(name), span));
+		}
+
+		if !self.bits.len().is_multiple_of(8) {
+			return Err(Error::new(ErrorKind::UnalignedLabel(name), span));
+		}
+
+		if self.labels.contains_key(&name) {
+			return Err(Error::new(ErrorKind::DuplicateLabel(name), span));
+		}
+
+		self.insert_label(name, span);
+		Ok(())
+	}
+
+	fn insert_label(&mut self, name: String, span: Span) {
+		let offset = self.parsed.len() + self.bits.len() / 8;
+		self.labels.insert(name.clone(), (offset, span));
+		self.label_order.push(name);
+	}
+
+	/// Hidden labels at the start and end of a length block. They can't
+	/// collide with real labels as those can't have braces in them.
+	fn block_labels(id: usize) -> (String, String) {
+		(format!("{{{}", id), format!("}}{}", id))
+	}
+
+	/// Parse the optional +N or -N after the width of a block's length, where
+	/// N may be an expression as in `u16+(HEADER*2){`
+	fn parse_adjust(&self, adjust: &str) -> Result<i128, ErrorKind> {
+		if adjust.is_empty() {
+			return Ok(0);
+		}
+
+		if !adjust.starts_with(['+', '-']) {
+			return Err(ErrorKind::InvalidExpression(adjust.to_string()));
+		}
+
+		self.evaluate(adjust)
+	}
+
+	/// Open a block with its length in front, from a spec like 16 or 32be+4
+	fn open_length_block(&mut self, spec: &str, span: Span, header: &Header) -> Result<(), Error> {
+		let invalid = || Error::new(ErrorKind::InvalidBlock(format!("u{}", spec)), span);
+
+		let (bitness, adjust) = match spec.find(['+', '-']) {
+			Some(split) => (&spec[..split], &spec[split..]),
+			None => (spec, ""),
+		};
+		let adjust = self.parse_adjust(adjust).map_err(|kind| match kind {
+			ErrorKind::InvalidExpression(_) => invalid(),
+			kind => Error::new(kind, span),
+		})?;
+		let (bitness, byteorder) = Self::split_byteorder(bitness);
+		let byteorder = byteorder.unwrap_or(header.byteorder);
+		let width = Self::parse_bitness(bitness).map_err(|kind| Error::new(kind, span))?;
+
+		let id = self.block_count;
+		self.block_count += 1;
+		let (start, end) = Self::block_labels(id);
+
+		self.pending_fixups.push(Fixup {
+			bit_offset: self.bits.len(),
+			width,
+			signed: false,
+			byteorder,
+			value: FixupValue::BlockLength(Expression::difference(end, start.clone(), adjust)),
+			text: format!("u{}", spec),
+			span,
+		});
+		self.push_field(0, width, byteorder, span, header);
+
+		if !self.bits.len().is_multiple_of(8) {
+			return Err(Error::new(ErrorKind::UnalignedBlock, span));
+		}
+		self.insert_label(start, span);
+
+		self.blocks.push(Block {
+			kind: BlockKind::Length(id),
+			span,
+		});
+
+		Ok(())
+	}
+
+	fn open_block(&mut self, opener: &Opener, span: Span, header: &Header) -> Result<(), Error> {
+		let kind = match opener {
+			Opener::Plain => BlockKind::Plain,
+			Opener::Named(name) => {
+				self.define_label(name.clone(), span)?;
+				BlockKind::Named(name.clone())
+			}
+			Opener::Length(spec) => return self.open_length_block(spec, span, header),
+			Opener::Varint(adjust) => {
+				let adjust = self.parse_adjust(adjust).map_err(|kind| match kind {
+					ErrorKind::InvalidExpression(_) => {
+						Error::new(ErrorKind::InvalidBlock(format!("varint{}", adjust)), span)
 					}
+					kind => Error::new(kind, span),
+				})?;
+
+				self.flush_bits(header)?;
+				BlockKind::Varint {
+					start: self.parsed.len(),
+					adjust,
+					labels: self.label_order.len(),
+					fixups: self.fixups.len(),
+					mappings: self.mappings.as_ref().map_or(0, Vec::len),
 				}
-				"lsb0" => {
-					if bitorder.replace(BitOrder::Lsb0).is_some() {
-						return Err(InvalidHeaderKind::TwoBitOrder.into());
-					}
+			}
+		};
+
+		self.blocks.push(Block { kind, span });
+		Ok(())
+	}
+
+	fn close_block(&mut self, span: Span, header: &Header) -> Result<(), Error> {
+		let block = self
+			.blocks
+			.pop()
+			.ok_or_else(|| Error::new(ErrorKind::UnmatchedBlockClose, span))?;
+
+		match block.kind {
+			BlockKind::Plain => (),
+			BlockKind::Named(name) => self.define_label(format!("{}.end", name), span)?,
+			BlockKind::Length(id) => {
+				if !self.bits.len().is_multiple_of(8) {
+					return Err(Error::new(ErrorKind::UnalignedBlock, span));
 				}
-				"big-endian" => {
-					if byteorder.replace(ByteOrder::BigEndian).is_some() {
-						return Err(InvalidHeaderKind::TwoByteOrder.into());
+
+				let (_, end) = Self::block_labels(id);
+				self.insert_label(end, span);
+			}
+			BlockKind::Varint {
+				start,
+				adjust,
+				labels,
+				fixups,
+				mappings,
+			} => {
+				self.flush_bits(header)?;
+
+				let length = (self.parsed.len() - start) as i128 + adjust;
+				let length = u128::try_from(length).map_err(|_| {
+					Error::new(ErrorKind::BlockLengthDoesNotFit(length), block.span)
+				})?;
+
+				let varint = Self::leb128(length);
+				self.parsed.splice(start..start, varint.iter().copied());
+
+				// Everything inside the block just moved
+				for name in &self.label_order[labels..] {
+					if let Some((offset, _)) = self.labels.get_mut(name) {
+						*offset += varint.len();
 					}
 				}
-				"little-endian" => {
-					if byteorder.replace(ByteOrder::LittleEndian).is_some() {
-						return Err(InvalidHeaderKind::TwoByteOrder.into());
-					}
+
+				for fixup in &mut self.fixups[fixups..] {
+					fixup.bit_offset += varint.len() * 8;
 				}
-				"twos-compliment" => {
-					if negativekind.replace(NegativeKind::TwosCompliment).is_none() {
-						return Err(InvalidHeaderKind::TwoNegativeKind.into());
+
+				if let Some(list) = &mut self.mappings {
+					for (bits, _) in &mut list[mappings..] {
+						*bits = bits.start + varint.len() * 8..bits.end + varint.len() * 8;
 					}
+					list.push((start * 8..(start + varint.len()) * 8, block.span));
 				}
-				"ones-compliment" => {
-					if negativekind.replace(NegativeKind::OnesCompliment).is_none() {
-						return Err(InvalidHeaderKind::TwoNegativeKind.into());
-					}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Carry out a directive like `!crc32(@data)`. `arguments` is what was
+	/// between the parentheses, if there were any.
+	fn directive(
+		&mut self,
+		name: &str,
+		arguments: Option<&str>,
+		span: Span,
+		header: &Header,
+	) -> Result<(), Error> {
+		let invalid_arguments = || {
+			Error::new(
+				ErrorKind::InvalidArguments {
+					directive: name.to_string(),
+					arguments: arguments.unwrap_or_default().to_string(),
+				},
+				span,
+			)
+		};
+
+		// Numbers in the arguments may be expressions, which can fail in
+		// their own way
+		let argument_error = |kind| match kind {
+			ErrorKind::InvalidExpression(_) => invalid_arguments(),
+			kind => Error::new(kind, span),
+		};
+
+		match name {
+			"include" => {
+				let path = arguments
+					.and_then(Self::split_string)
+					.filter(|(_, rest)| rest.trim().is_empty())
+					.ok_or_else(invalid_arguments)?
+					.0;
+				return self.include(&path, span, header);
+			}
+			"embed" => {
+				let (path, slice) = self
+					.parse_embed(arguments.unwrap_or_default())
+					.map_err(argument_error)?;
+				return self.embed(&path, slice, span, header);
+			}
+			"align" => {
+				let (alignment, fill) = self
+					.parse_fill(arguments.unwrap_or_default())
+					.map_err(argument_error)?;
+				if alignment == 0 {
+					return Err(invalid_arguments());
 				}
-				"sign-magnitude" => {
-					if negativekind.replace(NegativeKind::SignMagnitude).is_none() {
-						return Err(InvalidHeaderKind::TwoNegativeKind.into());
-					}
+
+				self.flush_bits(header)?;
+				let offset = self.parsed.len().next_multiple_of(alignment);
+				return self.pad(offset, fill, span, header);
+			}
+			"pad" => {
+				let (offset, fill) = self
+					.parse_fill(arguments.unwrap_or_default())
+					.map_err(argument_error)?;
+				return self.pad(offset, fill, span, header);
+			}
+			"else" => return Err(Error::new(ErrorKind::UnmatchedElse, span)),
+			"end" => return Err(Error::new(ErrorKind::UnmatchedEnd, span)),
+			_ => (),
+		}
+
+		if let Some(definition) = self.macros.get(name).cloned() {
+			return self.invoke(&definition, arguments.unwrap_or_default(), span, header);
+		}
+
+		let sum = if let Some(checksum) = Checksum::from_name(name) {
+			Some((Sum::Checksum(checksum), checksum.width()))
+		} else {
+			Self::digest(name).map_err(|kind| Error::new(kind, span))?
+		};
+
+		if let Some((sum, width)) = sum {
+			let (start, end) = arguments
+				.and_then(Self::parse_region)
+				.ok_or_else(invalid_arguments)?;
+
+			self.pending_fixups.push(Fixup {
+				bit_offset: self.bits.len(),
+				width,
+				signed: false,
+				byteorder: header.byteorder,
+				value: FixupValue::Region { sum, start, end },
+				text: format!("!{}", name),
+				span,
+			});
+			// Digests are wider than push_field can take, but zeroes are zeroes
+			self.push_bits(&vec![false; width as usize], span);
+
+			return Ok(());
+		}
+
+		Err(Error::new(
+			ErrorKind::UnknownDirective(name.to_string()),
+			span,
+		))
+	}
+
+	/// Whether `name` is taken by a directive, which a macro can't shadow
+	fn is_directive(name: &str) -> bool {
+		DIRECTIVES.contains(&name)
+			|| Checksum::from_name(name).is_some()
+			|| !matches!(Self::digest(name), Ok(None))
+	}
+
+	/// The digest called `name` and its width in bits, if there is one
+	#[cfg(feature = "digest")]
+	fn digest(name: &str) -> Result<Option<(Sum, u32)>, ErrorKind> {
+		Ok(Digest::from_name(name).map(|digest| (Sum::Digest(digest), digest.width())))
+	}
+
+	#[cfg(not(feature = "digest"))]
+	fn digest(name: &str) -> Result<Option<(Sum, u32)>, ErrorKind> {
+		match digest::NAMES.contains(&name) {
+			true => Err(ErrorKind::DigestUnavailable(name.to_string())),
+			false => Ok(None),
+		}
+	}
+
+	/// Parse the file `path` names as if it were written where the include
+	/// is. Byte order changes in it don't leak out and it must close any
+	/// blocks it opens.
+	fn include(&mut self, path: &str, span: Span, header: &Header) -> Result<(), Error> {
+		let from = self.sources[span.source].name.clone();
+		let (name, source) = self
+			.resolver
+			.resolve(path, from.as_deref())
+			.map_err(|error| {
+				Error::new(
+					ErrorKind::IncludeFailed {
+						path: path.to_string(),
+						reason: error.to_string(),
+					},
+					span,
+				)
+			})?;
+
+		let mut including = Some(span);
+		while let Some(directive) = including {
+			let file = &self.sources[directive.source];
+			if let Some(other) = &file.name {
+				if self.resolver.same_file(other, &name) {
+					return Err(Error::new(ErrorKind::IncludeCycle(name), span));
+				}
+			}
+			including = file.included_from;
+		}
+
+		self.sources.push(SourceFile {
+			name: Some(name),
+			included_from: Some(span),
+			expansion: None,
+		});
+		self.lower_nested(Cursor::new(&source, self.sources.len() - 1), header)
+	}
+
+	/// Define a macro from what follows `!macro` on its line and its body
+	fn define_macro(&mut self, definition: &str, body: &Body, span: Span) -> Result<(), Error> {
+		let invalid = || Error::new(ErrorKind::InvalidMacro(definition.to_string()), span);
+
+		let mut definition = Macro::parse(definition, body.span).ok_or_else(invalid)?;
+		let name = definition.name.clone();
+		if Self::is_directive(&name) {
+			return Err(invalid());
+		}
+		if self.macros.contains_key(&name) {
+			return Err(Error::new(ErrorKind::DuplicateMacro(name), span));
+		}
+
+		definition.body = body.text.clone();
+		self.macros.insert(name, Rc::new(definition));
+		Ok(())
+	}
+
+	/// Lower whichever branch of an `!if` its condition picks, which is the
+	/// part up to `!else` if it isn't zero and the part after if it is. The
+	/// other branch is skipped without being parsed. As with an include, the
+	/// branch must close any blocks it opens.
+	fn conditional(
+		&mut self,
+		condition: Option<&str>,
+		then: &Body,
+		otherwise: Option<&Body>,
+		span: Span,
+		header: &Header,
+	) -> Result<(), Error> {
+		let condition = condition.unwrap_or_default();
+		let invalid = || {
+			Error::new(
+				ErrorKind::InvalidArguments {
+					directive: "if".to_string(),
+					arguments: condition.to_string(),
+				},
+				span,
+			)
+		};
+
+		let condition = self.evaluate(condition).map_err(|kind| match kind {
+			ErrorKind::InvalidExpression(_) => invalid(),
+			kind => Error::new(kind, span),
+		})?;
+
+		let branch = if condition != 0 {
+			Some(then)
+		} else {
+			otherwise
+		};
+		match branch {
+			Some(branch) => self.lower_nested(Cursor::slice(&branch.text, branch.span), header),
+			None => Ok(()),
+		}
+	}
+
+	/// Parse the body of a macro with its parameters replaced by `arguments`.
+	/// As with an include, byte order changes don't leak out and the body
+	/// must close any blocks it opens.
+	fn invoke(
+		&mut self,
+		definition: &Rc<Macro>,
+		arguments: &str,
+		span: Span,
+		header: &Header,
+	) -> Result<(), Error> {
+		let arguments = macros::split_arguments(arguments);
+		if arguments.len() != definition.parameters.len() {
+			return Err(Error::new(
+				ErrorKind::MacroArguments {
+					name: definition.name.clone(),
+					expected: definition.parameters.len(),
+					found: arguments.len(),
+				},
+				span,
+			));
+		}
+
+		// A macro that invokes itself, however indirectly, would never stop
+		let mut invoking = Some(span);
+		while let Some(directive) = invoking {
+			let source = &self.sources[directive.source];
+			if let Some(expansion) = &source.expansion {
+				if expansion.definition.name == definition.name {
+					return Err(Error::new(
+						ErrorKind::MacroRecursion(definition.name.clone()),
+						span,
+					));
 				}
-				"padbits" => pad_bits = true,
-				_ => return Err(InvalidHeaderKind::InvalidProperty(split.into()).into()),
 			}
+			invoking = source.included_from;
 		}
 
-		if bitorder.is_none() {
-			return Err(InvalidHeaderKind::NoBitOrder.into());
-		} else if byteorder.is_none() {
-			return Err(InvalidHeaderKind::NoByteOrder.into());
-		} else {
-			Ok(Header {
-				bitorder: bitorder.unwrap(),
-				byteorder: byteorder.unwrap(),
-				negativekind: negativekind.unwrap_or(NegativeKind::TwosCompliment),
-				pad_bits,
-			})
+		let (text, expansion) = definition.expand(&arguments);
+		self.sources.push(SourceFile {
+			name: self.sources[definition.source()].name.clone(),
+			included_from: Some(span),
+			expansion: Some(expansion),
+		});
+		self.lower_nested(Cursor::new(&text, self.sources.len() - 1), header)
+	}
+
+	/// Put the bytes of the file `path` names where the embed is. `slice` is
+	/// the offset to start at and how many bytes to take, which is all of
+	/// them if there's no length.
+	fn embed(
+		&mut self,
+		path: &str,
+		slice: (usize, Option<usize>),
+		span: Span,
+		header: &Header,
+	) -> Result<(), Error> {
+		let from = self.sources[span.source].name.clone();
+		let bytes = self
+			.resolver
+			.resolve_bytes(path, from.as_deref())
+			.map_err(|error| {
+				Error::new(
+					ErrorKind::IncludeFailed {
+						path: path.to_string(),
+						reason: error.to_string(),
+					},
+					span,
+				)
+			})?;
+
+		let (offset, length) = slice;
+		let end = match length {
+			Some(length) => offset.checked_add(length),
+			None => Some(bytes.len()),
+		};
+		let bytes = end
+			.and_then(|end| bytes.get(offset..end))
+			.ok_or_else(|| Error::new(ErrorKind::EmbedOutOfRange(bytes.len()), span))?;
+
+		self.flush_bits(header)?;
+		self.parsed.extend_from_slice(bytes);
+
+		Ok(())
+	}
+
+	/// Write `fill` until the output is `offset` octets long
+	fn pad(&mut self, offset: usize, fill: u8, span: Span, header: &Header) -> Result<(), Error> {
+		self.flush_bits(header)?;
+
+		// The varint goes in front of everything in its block once it closes,
+		// so we can't know where we are until then
+		if self
+			.blocks
+			.iter()
+			.any(|block| matches!(block.kind, BlockKind::Varint { .. }))
+		{
+			return Err(Error::new(ErrorKind::PadInVarintBlock, span));
+		}
+
+		if offset < self.parsed.len() {
+			return Err(Error::new(
+				ErrorKind::PaddedPast {
+					offset,
+					length: self.parsed.len(),
+				},
+				span,
+			));
+		}
+
+		self.parsed.resize(offset, fill);
+		Ok(())
+	}
+
+	/// The arguments of align and pad, which are a number and an optional
+	/// octet to fill with that's zero if it isn't there
+	fn parse_fill(&self, arguments: &str) -> Result<(usize, u8), ErrorKind> {
+		let invalid = || ErrorKind::InvalidExpression(arguments.to_string());
+		let numbers = self.parse_numbers(arguments)?;
+
+		let (number, fill) = match numbers[..] {
+			[number] => (number, 0),
+			[number, fill] => (number, fill),
+			_ => return Err(invalid()),
+		};
+
+		Ok((
+			usize::try_from(number).map_err(|_| invalid())?,
+			u8::try_from(fill).map_err(|_| invalid())?,
+		))
+	}
+
+	/// The path of an embed with an optional offset and length after it, as
+	/// in `"blob.bin", 16, 0x20`
+	fn parse_embed(&self, arguments: &str) -> Result<(String, (usize, Option<usize>)), ErrorKind> {
+		let invalid = || ErrorKind::InvalidExpression(arguments.to_string());
+
+		let (path, rest) = Self::split_string(arguments).ok_or_else(invalid)?;
+		let rest = rest.trim();
+		if rest.is_empty() {
+			return Ok((path, (0, None)));
+		}
+
+		let numbers = self
+			.parse_numbers(rest.strip_prefix(',').ok_or_else(invalid)?)?
+			.into_iter()
+			.map(|number| usize::try_from(number).map_err(|_| invalid()))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		match numbers[..] {
+			[offset] => Ok((path, (offset, None))),
+			[offset, length] => Ok((path, (offset, Some(length)))),
+			_ => Err(invalid()),
+		}
+	}
+
+	/// Work out a comma separated list of expressions
+	fn parse_numbers(&self, arguments: &str) -> Result<Vec<i128>, ErrorKind> {
+		arguments
+			.split(',')
+			.map(|number| self.evaluate(number))
+			.collect()
+	}
+
+	/// Split a double quoted string with no escapes, like the path of an
+	/// include, off the front of the arguments. Returns it and what's left.
+	fn split_string(arguments: &str) -> Option<(String, &str)> {
+		let rest = arguments.trim_start().strip_prefix('"')?;
+		let end = rest.find('"')?;
+
+		Some((rest[..end].to_string(), &rest[end + 1..]))
+	}
+
+	/// A region is either a named block, as in `@data`, or the octets from
+	/// one label up to another, as in `@start, @end`. Returns the labels at
+	/// the start and end of the region.
+	fn parse_region(arguments: &str) -> Option<(String, String)> {
+		let labels = arguments
+			.split(',')
+			.map(|argument| {
+				argument
+					.trim()
+					.strip_prefix('@')
+					.filter(|name| Expression::is_label_name(name))
+			})
+			.collect::<Option<Vec<_>>>()?;
+
+		match labels[..] {
+			[name] => Some((name.to_string(), format!("{}.end", name))),
+			[start, end] => Some((start.to_string(), end.to_string())),
+			_ => None,
+		}
+	}
+
+	/// Unsigned LEB128, seven bits at a time with the high bit set on every
+	/// byte but the last.
+	fn leb128(mut value: u128) -> Vec<u8> {
+		let mut bytes = vec![];
+
+		loop {
+			let byte = (value & 0x7F) as u8;
+			value >>= 7;
+
+			if value == 0 {
+				bytes.push(byte);
+				return bytes;
+			}
+
+			bytes.push(byte | 0x80);
+		}
+	}
+
+	/// Work out the value of every fixup and write it over its placeholder.
+	/// Checksums go last, as they cover octets the other fixups write.
+	fn resolve_fixups(&mut self, header: &Header) -> Result<(), Error> {
+		let offset = |labels: &HashMap<String, (usize, Span)>, name: &str| {
+			labels.get(name).map(|(offset, _)| *offset)
+		};
+
+		let mut regions = vec![];
+		for fixup in std::mem::take(&mut self.fixups) {
+			let error = |kind| Error::new(kind, fixup.span);
+
+			let expression = match &fixup.value {
+				FixupValue::Expression(expression) | FixupValue::BlockLength(expression) => {
+					expression
+				}
+				FixupValue::Region { sum, start, end } => {
+					let start = offset(&self.labels, start)
+						.ok_or_else(|| error(ErrorKind::UndefinedLabel(start.clone())))?;
+					let end = offset(&self.labels, end)
+						.ok_or_else(|| error(ErrorKind::UndefinedLabel(end.clone())))?;
+
+					if end < start {
+						return Err(error(ErrorKind::BackwardsRegion));
+					}
+
+					regions.push((*sum, start..end, fixup));
+					continue;
+				}
+			};
+
+			let value = expression
+				.evaluate(&|name| offset(&self.labels, name))
+				.map_err(error)?;
+
+			let raw = Self::encode_value(value, fixup.width, fixup.signed, &header.negativekind);
+			let raw = raw.ok_or_else(|| match fixup.value {
+				FixupValue::BlockLength(_) => error(ErrorKind::BlockLengthDoesNotFit(value)),
+				_ => error(ErrorKind::DoesNotFit {
+					expression: fixup.text.clone(),
+					value,
+					width: fixup.width,
+				}),
+			})?;
+
+			let bits = Self::field_bits(raw, fixup.width, fixup.byteorder, &header.bitorder);
+			self.write_bits(fixup.bit_offset, bits, header);
+		}
+
+		// A checksum may cover another checksum, which then has to be worked
+		// out first. It may also cover its own field, which counts as zeroes
+		// the way the internet checksum expects. Fields never overlap, so the
+		// ones a region covers are a single run once sorted by position.
+		let bytes = |fixup: &Fixup| {
+			fixup.bit_offset / 8..(fixup.bit_offset + fixup.width as usize - 1) / 8 + 1
+		};
+		let mut by_position: Vec<usize> = (0..regions.len()).collect();
+		by_position.sort_by_key(|&index| regions[index].2.bit_offset);
+
+		// How many fields each region still waits on, and who waits on each
+		let mut waiting = vec![0; regions.len()];
+		let mut waiters = vec![vec![]; regions.len()];
+		for (index, (_, region, _)) in regions.iter().enumerate() {
+			let first =
+				by_position.partition_point(|&other| bytes(&regions[other].2).end <= region.start);
+			let last =
+				by_position.partition_point(|&other| bytes(&regions[other].2).start < region.end);
+			for &other in &by_position[first..last] {
+				if other != index {
+					waiting[index] += 1;
+					waiters[other].push(index);
+				}
+			}
+		}
+
+		let mut ready: Vec<usize> = (0..regions.len())
+			.filter(|&index| waiting[index] == 0)
+			.collect();
+		let mut done = vec![false; regions.len()];
+		while let Some(index) = ready.pop() {
+			let (sum, region, fixup) = &regions[index];
+			let data = &self.parsed[region.clone()];
+			let bits = match sum {
+				Sum::Checksum(checksum) => Self::field_bits(
+					checksum.compute(data),
+					fixup.width,
+					fixup.byteorder,
+					&header.bitorder,
+				),
+				// Digests are a string of octets, so byte order doesn't come into it
+				#[cfg(feature = "digest")]
+				Sum::Digest(digest) => digest
+					.compute(data)
+					.into_iter()
+					.flat_map(|byte| {
+						Self::field_bits(byte as u128, 8, ByteOrder::BigEndian, &header.bitorder)
+					})
+					.collect(),
+			};
+			self.write_bits(fixup.bit_offset, bits, header);
+
+			done[index] = true;
+			for &waiter in &waiters[index] {
+				waiting[waiter] -= 1;
+				if waiting[waiter] == 0 {
+					ready.push(waiter);
+				}
+			}
+		}
+
+		if let Some(index) = done.iter().position(|done| !done) {
+			return Err(Error::new(ErrorKind::ChecksumCycle, regions[index].2.span));
+		}
+
+		Ok(())
+	}
+
+	/// OR bits into the zeroes of a placeholder, starting `bit_offset` bits
+	/// into the output
+	fn write_bits(&mut self, bit_offset: usize, bits: Vec<bool>, header: &Header) {
+		for (index, bit) in bits.into_iter().enumerate() {
+			let position = bit_offset + index;
+			let mask = match header.bitorder {
+				BitOrder::Msb0 => 0x80 >> (position % 8),
+				BitOrder::Lsb0 => 1 << (position % 8),
+			};
+
+			if bit {
+				self.parsed[position / 8] |= mask;
+			}
+		}
+	}
+
+	/// Turn the pending bits into octets. Called whenever something that
+	/// writes whole bytes comes along and again at the end of the file.
+	fn flush_bits(&mut self, header: &Header) -> Result<(), Error> {
+		let mut padding = 0;
+
+		if !self.bits.len().is_multiple_of(8) {
+			if !header.pad_bits {
+				return Err(Error::new(ErrorKind::UnalignedBits, self.bits_span));
+			}
+
+			// Padding goes on the side of the octet that is written last, so
+			// a partial run always ends up in the least significant bits.
+			while !self.bits.len().is_multiple_of(8) {
+				match header.bitorder {
+					BitOrder::Msb0 => {
+						self.bits.insert(0, false);
+						padding += 1;
+					}
+					BitOrder::Lsb0 => self.bits.push(false),
+				}
+			}
+		}
+
+		// Padding went in front of anything that's mapped to the bits. Those
+		// came since the last flush, so they're at the end.
+		let base = self.parsed.len() * 8;
+		if padding > 0 {
+			let mappings = self.mappings.iter_mut().flatten().rev();
+			for (bits, _) in mappings.take_while(|(bits, _)| bits.end > base) {
+				if bits.start >= base {
+					bits.start += padding;
+				}
+				bits.end += padding;
+			}
+			let marks = self.map_marks.iter_mut().rev();
+			for (_, start) in marks.take_while(|(_, start)| *start >= base) {
+				*start += padding;
+			}
+		}
+
+		let start = self.parsed.len() * 8 + padding;
+		for mut fixup in self.pending_fixups.drain(..) {
+			fixup.bit_offset += start;
+			self.fixups.push(fixup);
+		}
+
+		match header.bitorder {
+			BitOrder::Msb0 => self.parsed.extend_from_slice(self.bits.as_raw_slice()),
+			BitOrder::Lsb0 => self.parsed.extend(
+				self.bits
+					.as_raw_slice()
+					.iter()
+					.map(|byte| byte.reverse_bits()),
+			),
+		}
+		self.bits = BitVec::new();
+
+		Ok(())
+	}
+
+	/// Parse the properties of the header line. `span` is the span of the line
+	/// itself, which lets us point at the offending property.
+	fn parse_header(string: &str, span: Span) -> Result<Header, Error> {
+		let mut bitorder = None;
+		let mut byteorder = None;
+		let mut negativekind = None;
+		let mut pad_bits = false;
+
+		for (property, property_span) in Self::properties(string, span) {
+			let error = |kind: InvalidHeaderKind| Err(Error::new(kind.into(), property_span));
+
+			match property {
+				"msb0" => {
+					if bitorder.replace(BitOrder::Msb0).is_some() {
+						return error(InvalidHeaderKind::TwoBitOrder);
+					}
+				}
+				"lsb0" => {
+					if bitorder.replace(BitOrder::Lsb0).is_some() {
+						return error(InvalidHeaderKind::TwoBitOrder);
+					}
+				}
+				"big-endian" => {
+					if byteorder.replace(ByteOrder::BigEndian).is_some() {
+						return error(InvalidHeaderKind::TwoByteOrder);
+					}
+				}
+				"little-endian" => {
+					if byteorder.replace(ByteOrder::LittleEndian).is_some() {
+						return error(InvalidHeaderKind::TwoByteOrder);
+					}
+				}
+				"twos-compliment" => {
+					if negativekind.replace(NegativeKind::TwosCompliment).is_some() {
+						return error(InvalidHeaderKind::TwoNegativeKind);
+					}
+				}
+				"ones-compliment" => {
+					if negativekind.replace(NegativeKind::OnesCompliment).is_some() {
+						return error(InvalidHeaderKind::TwoNegativeKind);
+					}
+				}
+				"sign-magnitude" => {
+					if negativekind.replace(NegativeKind::SignMagnitude).is_some() {
+						return error(InvalidHeaderKind::TwoNegativeKind);
+					}
+				}
+				"padbits" => pad_bits = true,
+				property => return error(InvalidHeaderKind::InvalidProperty(property.into())),
+			}
+		}
+
+		let missing = |kind: InvalidHeaderKind| Error::new(kind.into(), span);
+		let bitorder = bitorder.ok_or_else(|| missing(InvalidHeaderKind::NoBitOrder))?;
+		let byteorder = byteorder.ok_or_else(|| missing(InvalidHeaderKind::NoByteOrder))?;
+
+		Ok(Header {
+			bitorder,
+			byteorder,
+			negativekind: negativekind.unwrap_or(NegativeKind::TwosCompliment),
+			pad_bits,
+		})
+	}
+
+	/// Parse a `~` line that comes after the header. These may only change the
+	/// byte order, which then applies until the end of the file or the next one.
+	fn parse_byteorder_change(string: &str, span: Span) -> Result<ByteOrder, Error> {
+		let mut byteorder = None;
+
+		for (property, property_span) in Self::properties(string, span) {
+			let error = |kind: InvalidHeaderKind| Err(Error::new(kind.into(), property_span));
+
+			match property {
+				"big-endian" => {
+					if byteorder.replace(ByteOrder::BigEndian).is_some() {
+						return error(InvalidHeaderKind::TwoByteOrder);
+					}
+				}
+				"little-endian" => {
+					if byteorder.replace(ByteOrder::LittleEndian).is_some() {
+						return error(InvalidHeaderKind::TwoByteOrder);
+					}
+				}
+				"msb0" | "lsb0" | "twos-compliment" | "ones-compliment" | "sign-magnitude"
+				| "padbits" => return error(InvalidHeaderKind::HeaderOnly(property.into())),
+				property => return error(InvalidHeaderKind::InvalidProperty(property.into())),
+			}
+		}
+
+		byteorder.ok_or_else(|| Error::new(InvalidHeaderKind::NoByteOrder.into(), span))
+	}
+
+	/// The space separated properties of a header line with their spans
+	fn properties(string: &str, span: Span) -> impl Iterator<Item = (&str, Span)> {
+		let mut offset = 0;
+
+		string.split(' ').filter_map(move |split| {
+			let property_span = span.within(string, offset..offset + split.len());
+			offset += split.len() + 1;
+
+			let property = split.trim_end();
+			(!property.is_empty()).then_some((property, property_span))
+		})
+	}
+
+	/// Sized values may end their width with be or le to use that byte order
+	/// instead of the current one, as in u32be=1
+	fn split_byteorder(bitness: &str) -> (&str, Option<ByteOrder>) {
+		if let Some(width) = bitness.strip_suffix("be") {
+			(width, Some(ByteOrder::BigEndian))
+		} else if let Some(width) = bitness.strip_suffix("le") {
+			(width, Some(ByteOrder::LittleEndian))
+		} else {
+			(bitness, None)
+		}
+	}
+
+	/// Parse the width of a sized decimal, which may be anywhere from 1 to 128
+	fn parse_bitness(bitness: &str) -> Result<u32, ErrorKind> {
+		match bitness.parse() {
+			Ok(width) if (1..=128).contains(&width) && !bitness.starts_with('+') => Ok(width),
+			_ => Err(ErrorKind::InvalidBitness(bitness.to_string())),
+		}
+	}
+
+	/// Returns the encoded value and its width in bits
+	fn signed_field<S: AsRef<str>>(
+		bitness: S,
+		value: S,
+		negativekind: &NegativeKind,
+	) -> Result<(u128, u32), ErrorKind> {
+		let width = Self::parse_bitness(bitness.as_ref())?;
+
+		Self::split_sign(value.as_ref())
+			.and_then(|(negative, magnitude)| {
+				Self::encode_signed(negative, magnitude, width, negativekind)
+			})
+			.map(|raw| (raw, width))
+			.ok_or_else(|| ErrorKind::InvalidSignedDecimal(value.as_ref().to_string()))
+	}
+
+	fn signed_smallest_le_bytes(
+		negative: bool,
+		magnitude: u128,
+		negativekind: &NegativeKind,
+	) -> Option<Vec<u8>> {
+		[1, 2, 4, 8, 16].into_iter().find_map(|width| {
+			Self::encode_signed(negative, magnitude, width * 8, negativekind)
+				.map(|raw| raw.to_le_bytes()[..width as usize].to_vec())
+		})
+	}
+
+	/// Parse an integer as it would be written in hext, with an optional sign
+	/// and 0x, 0o, or 0b prefix. None if it isn't one or doesn't fit an i128.
+	pub fn parse_integer(string: &str) -> Option<i128> {
+		let (negative, magnitude) = Self::split_sign(string)?;
+		match negative {
+			true => 0i128.checked_sub_unsigned(magnitude),
+			false => i128::try_from(magnitude).ok(),
+		}
+	}
+
+	/// Splits a decimal string into its sign and magnitude. We can't parse
+	/// straight to a signed type because the sign has to survive a zero
+	/// magnitude for the representations that have a negative zero.
+	fn split_sign(string: &str) -> Option<(bool, u128)> {
+		let (negative, digits) = match string.strip_prefix('-') {
+			Some(digits) => (true, digits),
+			None => (false, string.strip_prefix('+').unwrap_or(string)),
+		};
+
+		Self::parse_magnitude(digits).map(|magnitude| (negative, magnitude))
+	}
+
+	/// Parse an unsigned number that may have a 0x, 0o, or 0b radix prefix, in
+	/// either case. Underscores can be used to separate digits, like 0b1010_0101.
+	fn parse_magnitude(string: &str) -> Option<u128> {
+		let (radix, digits) = match string.get(..2).map(str::to_ascii_lowercase).as_deref() {
+			Some("0x") => (16, &string[2..]),
+			Some("0o") => (8, &string[2..]),
+			Some("0b") => (2, &string[2..]),
+			_ => (10, string),
+		};
+
+		// from_str_radix would happily take a sign, as in "-+1", and we don't
+		// want a separator before there are any digits
+		if !digits.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+			return None;
+		}
+
+		u128::from_str_radix(&digits.replace('_', ""), radix).ok()
+	}
+
+	/// Encode a sign and magnitude into the low `bits` bits of a u128 using the
+	/// chosen negative number representation. None if it does not fit.
+	fn encode_signed(
+		negative: bool,
+		magnitude: u128,
+		bits: u32,
+		negativekind: &NegativeKind,
+	) -> Option<u128> {
+		let mask = u128::MAX >> (128 - bits);
+		let max_positive = mask >> 1;
+
+		if !negative {
+			return (magnitude <= max_positive).then_some(magnitude);
+		}
+
+		match negativekind {
+			NegativeKind::TwosCompliment => {
+				(magnitude <= max_positive + 1).then_some(magnitude.wrapping_neg() & mask)
+			}
+			NegativeKind::OnesCompliment => {
+				(magnitude <= max_positive).then_some(!magnitude & mask)
+			}
+			NegativeKind::SignMagnitude => {
+				(magnitude <= max_positive).then_some(magnitude | (max_positive + 1))
+			}
+		}
+	}
+
+	/// Encode a value into the low `width` bits of a u128, signed or not.
+	/// None if it does not fit.
+	fn encode_value(
+		value: i128,
+		width: u32,
+		signed: bool,
+		negativekind: &NegativeKind,
+	) -> Option<u128> {
+		if signed {
+			Self::encode_signed(value < 0, value.unsigned_abs(), width, negativekind)
+		} else {
+			u128::try_from(value)
+				.ok()
+				.filter(|raw| width == 128 || raw >> width == 0)
+		}
+	}
+
+	/// Returns the value and its width in bits
+	fn unsigned_field<S: AsRef<str>>(bitness: S, value: S) -> Result<(u128, u32), ErrorKind> {
+		let width = Self::parse_bitness(bitness.as_ref())?;
+		let value = value.as_ref();
+
+		match Self::parse_magnitude(value.strip_prefix('+').unwrap_or(value)) {
+			Some(raw) if width == 128 || raw >> width == 0 => Ok((raw, width)),
+			_ => Err(ErrorKind::InvalidUnsignedDecimal(value.to_string())),
+		}
+	}
+
+	/// Returns the IEEE-754 encoding of the float and its width in bits. Along
+	/// with anything Rust can parse there is `nan`, which is the quiet NaN, and
+	/// `nan(payload)` for a NaN with exactly that mantissa.
+	fn float_field<S: AsRef<str>>(bitness: S, value: S) -> Result<(u128, u32), ErrorKind> {
+		let value = value.as_ref();
+		let invalid = || ErrorKind::InvalidFloat(value.to_string());
+
+		let (width, mantissa_bits) = match bitness.as_ref() {
+			"16" => (16, 10),
+			"32" => (32, 23),
+			"64" => (64, 52),
+			_ => return Err(ErrorKind::InvalidFloatWidth(bitness.as_ref().to_string())),
+		};
+
+		let (negative, unsigned) = match value.strip_prefix('-') {
+			Some(unsigned) => (true, unsigned),
+			None => (false, value.strip_prefix('+').unwrap_or(value)),
+		};
+		let sign = (negative as u128) << (width - 1);
+
+		let nan_payload = if unsigned == "nan" {
+			Some(1 << (mantissa_bits - 1))
+		} else if let Some(payload) = unsigned
+			.strip_prefix("nan(")
+			.and_then(|rest| rest.strip_suffix(')'))
+		{
+			match Self::parse_magnitude(payload) {
+				Some(payload) if payload != 0 && payload >> mantissa_bits == 0 => Some(payload),
+				_ => return Err(invalid()),
+			}
+		} else {
+			None
+		};
+
+		if let Some(payload) = nan_payload {
+			let exponent = (1u128 << (width - 1 - mantissa_bits)) - 1;
+			return Ok((sign | exponent << mantissa_bits | payload, width));
+		}
+
+		// Rust's parser takes its own sign and spellings of NaN, neither of
+		// which we want to let through a second time
+		if unsigned.starts_with(['+', '-']) || unsigned.to_ascii_lowercase().contains("nan") {
+			return Err(invalid());
+		}
+
+		let raw = match width {
+			16 => Self::f16_bits(unsigned, unsigned.parse().map_err(|_| invalid())?) as u128,
+			32 => unsigned.parse::<f32>().map_err(|_| invalid())?.to_bits() as u128,
+			_ => unsigned.parse::<f64>().map_err(|_| invalid())?.to_bits() as u128,
+		};
+
+		Ok((sign | raw, width))
+	}
+
+	/// Convert a positive decimal to a half precision float, rounding to
+	/// nearest with ties to even the way the wider conversions do. `value` is
+	/// `decimal` already rounded to an f64, which is only ever wrong for us
+	/// when it lands exactly halfway between two halfs and `decimal` didn't.
+	fn f16_bits(decimal: &str, value: f64) -> u16 {
+		let bits = value.to_bits();
+		let exponent = ((bits >> 52) & 0x7FF) as i32;
+		let mantissa = bits & ((1 << 52) - 1);
+
+		if exponent == 0x7FF {
+			// We only get here with infinity, NaN is handled by the caller
+			return 0x7C00;
+		}
+
+		// Shift the mantissa down to ten bits, keeping track of what falls off
+		let round_shift = |value: u64, shift: u32| -> u64 {
+			if shift >= 64 {
+				return 0;
+			}
+
+			let kept = value >> shift;
+			let dropped = value & ((1 << shift) - 1);
+			let half = 1 << (shift - 1);
+
+			let up = match dropped.cmp(&half) {
+				Ordering::Greater => true,
+				Ordering::Less => false,
+				Ordering::Equal => match Self::compare_decimal(decimal, bits) {
+					Ordering::Greater => true,
+					Ordering::Less => false,
+					Ordering::Equal => kept & 1 == 1,
+				},
+			};
+			kept + up as u64
+		};
+
+		let half_exponent = exponent - 1023 + 15;
+		let half = if half_exponent >= 31 {
+			0x7C00
+		} else if half_exponent <= 0 {
+			// Subnormal, which has an implied exponent of 1 and no implied
+			// leading one. Rounding up may carry into the smallest normal.
+			let shift = (42 + 1 - half_exponent) as u32;
+			round_shift(mantissa | (1 << 52), shift)
+		} else {
+			// Rounding may carry into the exponent, all the way to infinity
+			((half_exponent as u64) << 10) + round_shift(mantissa, 42)
+		};
+
+		half.min(0x7C00) as u16
+	}
+
+	/// Compare a positive decimal like `1.5e-3` exactly with the f64 whose
+	/// bits are `bits`. The f64 has to be halfway between two halfs, which is
+	/// few enough digits to write out in full.
+	fn compare_decimal(decimal: &str, bits: u64) -> Ordering {
+		// The f64 as a whole number of units of 10^-scale
+		let exponent = ((bits >> 52) & 0x7FF) as i32;
+		let mantissa = match exponent {
+			0 => bits & ((1 << 52) - 1),
+			_ => bits & ((1 << 52) - 1) | 1 << 52,
+		};
+		let (whole, scale) = match mantissa.trailing_zeros() {
+			64 => (0, 0),
+			zeros => match exponent.max(1) - 1075 + zeros as i32 {
+				power @ 0.. => ((mantissa as u128 >> zeros) << power, 0),
+				power => (
+					(mantissa as u128 >> zeros) * 5u128.pow(-power as u32),
+					-power,
+				),
+			},
+		};
+
+		// Both as significant digits and where the decimal point goes relative
+		// to the first of them
+		let (float_digits, float_point) = Self::decimal_digits(&whole.to_string(), -scale);
+		// An exponent too big for an i32 can't be anywhere near a half
+		let (mantissa, exponent) = match decimal.split_once(['e', 'E']) {
+			Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().unwrap_or(0)),
+			None => (decimal, 0),
+		};
+		let (digits, point) = match mantissa.split_once('.') {
+			Some((int, frac)) => (format!("{}{}", int, frac), exponent - frac.len() as i32),
+			None => (mantissa.to_string(), exponent),
+		};
+		let (digits, point) = Self::decimal_digits(&digits, point);
+
+		match (digits.is_empty(), float_digits.is_empty()) {
+			(true, true) => Ordering::Equal,
+			(true, false) => Ordering::Less,
+			(false, true) => Ordering::Greater,
+			(false, false) => point
+				.cmp(&float_point)
+				.then_with(|| digits.cmp(&float_digits)),
+		}
+	}
+
+	/// The significant digits of `digits` × 10^`exponent`, without leading
+	/// or trailing zeros, and the power of ten of the first one
+	fn decimal_digits(digits: &str, exponent: i32) -> (String, i32) {
+		let trimmed = digits.trim_start_matches('0');
+		let point = exponent + trimmed.len() as i32;
+		(trimmed.trim_end_matches('0').to_string(), point)
+	}
+
+	fn unsigned_smallest_le_bytes(large: u128) -> Vec<u8> {
+		if large > u64::MAX as u128 {
+			large.to_le_bytes().to_vec()
+		} else if large > u32::MAX as u128 {
+			(large as u64).to_le_bytes().to_vec()
+		} else if large > u16::MAX as u128 {
+			(large as u32).to_le_bytes().to_vec()
+		} else if large > u8::MAX as u128 {
+			(large as u16).to_le_bytes().to_vec()
+		} else {
+			(large as u8).to_le_bytes().to_vec()
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn pares_header_success() {
+		// Recognizes the keytwords...
+		assert_eq!(
+			Hext::parse_header("msb0 big-endian", Span::default()).unwrap(),
+			Header {
+				byteorder: ByteOrder::BigEndian,
+				bitorder: crate::BitOrder::Msb0,
+				negativekind: NegativeKind::TwosCompliment,
+				pad_bits: false
+			}
+		);
+
+		assert_eq!(
+			Hext::parse_header("lsb0 little-endian", Span::default()).unwrap(),
+			Header {
+				byteorder: ByteOrder::LittleEndian,
+				bitorder: crate::BitOrder::Lsb0,
+				negativekind: NegativeKind::TwosCompliment,
+				pad_bits: false
+			}
+		);
+
+		// ...In either order
+		assert_eq!(
+			Hext::parse_header("big-endian lsb0", Span::default()).unwrap(),
+			Header {
+				byteorder: ByteOrder::BigEndian,
+				bitorder: crate::BitOrder::Lsb0,
+				negativekind: NegativeKind::TwosCompliment,
+				pad_bits: false
+			}
+		);
+	}
+
+	#[test]
+	fn parse_header_fail_twobits() {
+		assert_eq!(
+			Hext::parse_header("lsb0 msb0", Span::default())
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::TwoBitOrder)
+		)
+	}
+
+	#[test]
+	fn parse_header_fail_twobytes() {
+		assert_eq!(
+			Hext::parse_header("little-endian big-endian", Span::default())
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::TwoByteOrder)
+		)
+	}
+
+	#[test]
+	fn parse_header_fail_nobits() {
+		assert_eq!(
+			Hext::parse_header("big-endian", Span::default())
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::NoBitOrder)
+		)
+	}
+
+	#[test]
+	fn parse_header_fail_nobytes() {
+		assert_eq!(
+			Hext::parse_header("msb0", Span::default())
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::NoByteOrder)
+		)
+	}
+
+	#[test]
+	fn parse_header_fail_invalidproperty() {
+		assert_eq!(
+			Hext::parse_header("lsb0 big-endian invalidproperty", Span::default())
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::InvalidProperty("invalidproperty".into()))
+		)
+	}
+
+	#[test]
+	fn parse_header_negativekind() {
+		assert_eq!(
+			Hext::parse_header("msb0 big-endian sign-magnitude", Span::default()).unwrap(),
+			Header {
+				byteorder: ByteOrder::BigEndian,
+				bitorder: crate::BitOrder::Msb0,
+				negativekind: NegativeKind::SignMagnitude,
+				pad_bits: false
+			}
+		);
+	}
+
+	#[test]
+	fn parse_header_fail_twonegativekinds() {
+		assert_eq!(
+			Hext::parse_header(
+				"msb0 big-endian ones-compliment sign-magnitude",
+				Span::default()
+			)
+			.unwrap_err()
+			.kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::TwoNegativeKind)
+		)
+	}
+
+	//## Bytes tests ##
+	#[test]
+	fn test_onebyte() {
+		let test = "~little-endian msb0\n41";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_only_comment() {
+		let test = "~little-endian msb0\n# Comment";
+
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![]);
+	}
+
+	#[test]
+	fn test_1byte_comment() {
+		let test = "~little-endian msb0\n41 #A";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_byte_nospace_comment() {
+		let test = "~little-endian msb0\n41#A";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_2byte_multiline() {
+		let test = "~little-endian msb0\n41\n42";
+		let cmp = vec![0x41, 0x42];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_2bytes_nospace() {
+		let test = "~little-endian msb0\n4142";
+		let cmp = vec![0x41, 0x42];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	//## Bit Tests ##
+	#[test]
+	fn test_8bits() {
+		let test = "~little-endian msb0\n.01000001";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bits_hex10() {
+		let test = "~little-endian msb0\n.01000001 10";
+		let cmp = vec![0x41, 0x10];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bit_comment() {
+		let test = "~little-endian msb0\n.01000001 # A";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp)
+	}
+
+	#[test]
+	fn test_8bit_nospace_comment() {
+		let test = "~little-endian msb0\n.01000001#A";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_1bit() {
+		let test = "~little-endian msb0 padbits\n.1";
+		let cmp = vec![0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bits_halved_space() {
+		let test_space = "~little-endian msb0\n.0100 .0010";
+		let cmp = vec![0x42];
+
+		assert_eq!(Hext::new().parse(test_space).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bits_halved_line() {
+		let test_line = "~little-endian msb0\n.0100\n.0010";
+		let cmp = vec![0x42];
+
+		assert_eq!(Hext::new().parse(test_line).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bits_halved_line_comments() {
+		let test_line_comments = "~little-endian msb0\n.0100#Half of capital letter\n.0010 # B";
+		let cmp = vec![0x42];
+
+		assert_eq!(Hext::new().parse(test_line_comments).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_1bit_then_byte() {
+		let test = "~little-endian msb0 padbits\n.1 41";
+		let cmp = vec![0x01, 0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bits_lsb0() {
+		let test = "~little-endian lsb0\n.10000010";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_8bits_halved_lsb0() {
+		let test = "~little-endian lsb0\n.1000 .0010";
+		let cmp = vec![0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_1bit_lsb0() {
+		let test = "~little-endian lsb0 padbits\n.1";
+		let cmp = vec![0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_3bits_padded_lsb0() {
+		// The first bit written is the least significant, padding fills the top
+		let test = "~little-endian lsb0 padbits\n.110";
+		let cmp = vec![0x03];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn test_bits_between_bytes_lsb0() {
+		let test = "~little-endian lsb0\n41 .1 .0000 .010 42";
+		let cmp = vec![0x41, 0x41, 0x42];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_unaligned_bit_lsb0() {
+		let test = "~little-endian lsb0\n.101 41";
+		let cmp = ErrorKind::UnalignedBits;
+
+		assert_eq!(Hext::new().parse(test).unwrap_err().kind(), &cmp);
+	}
+
+	//## Literal Tests ##
+	#[test]
+	fn literal_multibyte() {
+		let test = "~big-endian lsb0\n\"🥺\"";
+		let cmp = vec![0xf0, 0x9f, 0xa5, 0xba];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	//## Decimal Tests ##
+	#[test]
+	fn decimal_unsized_u8() {
+		let test = "~big-endian lsb0\n=200";
+		let cmp = vec![200];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_unsized_i8() {
+		let test = "~big-endian lsb0\n=-127";
+		let cmp = (-127i8).to_be_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_unsized_u32() {
+		let test = "~little-endian lsb0\n=65536";
+		let cmp = 65536u32.to_le_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_unsized_i32() {
+		let test = "~little-endian lsb0\n=-40000";
+		let cmp = (-40000i32).to_le_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_sized_u16() {
+		let test = "~little-endian lsb0\nu16=65534";
+		let cmp = 65534u16.to_le_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_overflow_sized_u16() {
+		let test = "~little-endian lsb0\nu16=65536";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidUnsignedDecimal("65536".into())
+		);
+	}
+
+	//## Negative Representation Tests ##
+	#[test]
+	fn decimal_sized_i8_ones_compliment() {
+		let test = "~big-endian msb0 ones-compliment\ni8=-5 i8=5";
+		let cmp = vec![0xFA, 0x05];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_sized_i16_sign_magnitude() {
+		let test = "~big-endian msb0 sign-magnitude\ni16=-5";
+		let cmp = vec![0x80, 0x05];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_sized_negative_zero() {
+		let test = "~little-endian msb0 twos-compliment\ni8=-0";
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![0x00]);
+
+		let test = "~little-endian msb0 ones-compliment\ni16=-0";
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![0xFF, 0xFF]);
+
+		let test = "~little-endian msb0 sign-magnitude\ni16=-0";
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![0x00, 0x80]);
+	}
+
+	#[test]
+	fn decimal_unsized_sign_magnitude() {
+		let test = "~big-endian msb0 sign-magnitude\n=-127 =-0";
+		let cmp = vec![0xFF, 0x80];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_unsized_ones_compliment_widens() {
+		// -128 has no 8-bit ones' compliment form, so it takes two bytes
+		let test = "~big-endian msb0 ones-compliment\n=-128";
+		let cmp = vec![0xFF, 0x7F];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_overflow_sized_i8_sign_magnitude() {
+		let test = "~big-endian msb0 sign-magnitude\ni8=-128";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidSignedDecimal("-128".into())
+		);
+	}
+
+	#[test]
+	fn decimal_overflow_sized_i8() {
+		let test = "~big-endian msb0\ni8=-129";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidSignedDecimal("-129".into())
+		);
+	}
+
+	//## Bitfield Decimal Tests ##
+	#[test]
+	fn field_whole_octets_not_padded() {
+		let test = "~big-endian msb0 padbits\ni32=-70000\n.0";
+		let cmp = vec![0xFF, 0xFE, 0xEE, 0x90, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_then_padded_run() {
+		let test = "~big-endian msb0 padbits\nu64=1 .000000000001";
+		let cmp = vec![0, 0, 0, 0, 0, 0, 0, 0x01, 0x00, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_packs_with_bits() {
+		// GIF's packed Logical Screen Descriptor field
+		let test = "~little-endian msb0\nu1=1 u3=0 .0 u3=1";
+		let cmp = vec![0x81];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_lsb0_fills_from_bottom() {
+		let test = "~little-endian lsb0\nu1=1 u2=2 u5=0";
+		let cmp = vec![0x05];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_crosses_octets_little_endian_lsb0() {
+		// The DEFLATE way: every value is written least significant bit first
+		let test = "~little-endian lsb0\nu4=15 u12=291";
+		let cmp = vec![0x3F, 0x12];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_crosses_octets_big_endian_msb0() {
+		let test = "~big-endian msb0\nu12=291 u4=15";
+		let cmp = vec![0x12, 0x3F];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_unaligned_whole_octets_keep_byte_order() {
+		let test = "~little-endian msb0\n.1111 u16=4660 .0000";
+		let cmp = vec![0xF3, 0x41, 0x20];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_signed_odd_width() {
+		let test = "~big-endian msb0\ni12=-100 i4=-1";
+		let cmp = vec![0xF9, 0xCF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_u24() {
+		let test = "~little-endian msb0\nu24=65536";
+		let cmp = vec![0x00, 0x00, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn field_padded() {
+		let test = "~little-endian msb0 padbits\nu3=5";
+		let cmp = vec![0x05];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_field_overflow() {
+		let test = "~little-endian msb0\nu3=8 .00000";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidUnsignedDecimal("8".into())
+		);
+	}
+
+	#[test]
+	fn ftest_field_unaligned() {
+		let test = "~little-endian msb0\nu3=1 41";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnalignedBits
+		);
+	}
+
+	#[test]
+	fn ftest_field_bitness() {
+		let test = "~little-endian msb0\nu129=1";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidBitness("129".into())
+		);
+
+		let test = "~little-endian msb0\ni0=0";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidBitness("0".into())
+		);
+	}
+
+	//## 128-bit Tests ##
+	#[test]
+	fn decimal_sized_u128() {
+		let test = "~little-endian msb0\nu128=340282366920938463463374607431768211455";
+		let cmp = u128::MAX.to_le_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_sized_i128_big_endian() {
+		let test = "~big-endian msb0\ni128=-170141183460469231731687303715884105728";
+		let cmp = i128::MIN.to_be_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_unsized_u128() {
+		let test = "~big-endian msb0\n=18446744073709551616";
+		let cmp = (u64::MAX as u128 + 1).to_be_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn decimal_unsized_i128() {
+		let test = "~little-endian msb0\n=-9223372036854775809";
+		let cmp = (i64::MIN as i128 - 1).to_le_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_decimal_overflow_sized_u128() {
+		let test = "~little-endian msb0\nu128=340282366920938463463374607431768211456";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidUnsignedDecimal("340282366920938463463374607431768211456".into())
+		);
+	}
+
+	//## Float Tests ##
+	#[test]
+	fn float_f32() {
+		let test = "~little-endian msb0\nf32=1.1";
+		let cmp = 1.1f32.to_le_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_f64_big_endian() {
+		let test = "~big-endian msb0\nf64=-1e-9";
+		let cmp = (-1e-9f64).to_be_bytes().to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_f16() {
+		let test = "~big-endian msb0\nf16=0.5 f16=-2 f16=65504 f16=1e-7 f16=-0";
+		let cmp = vec![0x38, 0x00, 0xC0, 0x00, 0x7B, 0xFF, 0x00, 0x02, 0x80, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_f16_rounding() {
+		// 1 + 2^-11 is exactly between two halfs and goes to the even one,
+		// 65520 is the first value that rounds up to infinity.
+		let test = "~big-endian msb0\nf16=1.00048828125 f16=1.00146484375 f16=65520";
+		let cmp = vec![0x3C, 0x00, 0x3C, 0x02, 0x7C, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_f16_rounded_once() {
+		// These are a hair either side of 1 + 2^-11, which is halfway between
+		// two halfs, but round to it as an f64
+		let test = "~big-endian msb0\nf16=1.00048828125000000000000001 f16=1.00048828124999999999999999 f16=65519.99999999999999999999";
+		let cmp = vec![0x3C, 0x01, 0x3C, 0x00, 0x7B, 0xFF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_specials() {
+		let test = "~big-endian msb0\nf32=inf f32=-inf f32=nan f16=-nan f64=nan(1)";
+		let cmp = vec![
+			0x7F, 0x80, 0x00, 0x00, 0xFF, 0x80, 0x00, 0x00, 0x7F, 0xC0, 0x00, 0x00, 0xFE, 0x00,
+			0x7F, 0xF0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+		];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_is_not_hex() {
+		let test = "~big-endian msb0\nf3 f16=1 ff";
+		let cmp = vec![0xF3, 0x3C, 0x00, 0xFF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn float_hex_then_value() {
+		// Only f16, f32, and f64 are floats. Anything else starting with f is
+		// an octet.
+		for (test, cmp) in [
+			("ff=1", vec![0xFF, 0x01]),
+			("f3=-1", vec![0xF3, 0xFF]),
+			("f8=1", vec![0xF8, 0x01]),
+			("ffu32=4294967295", vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+			("fau16=300", vec![0xFA, 0x01, 0x2C]),
+			(".10101010ff=+5", vec![0xAA, 0xFF, 0x05]),
+			("fa\"a=b\"", vec![0xFA, 0x61, 0x3D, 0x62]),
+		] {
+			let test = format!("~big-endian msb0\n{}", test);
+			assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+		}
+	}
+
+	#[test]
+	fn float_many_ff_octets() {
+		// Deciding whether an f starts a float only looks at its own token
+		let test = format!("~big-endian msb0\n{}", "ff ".repeat(300_000));
+		let cmp = vec![0xFF; 300_000];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_float_width() {
+		// The lexer only makes floats of the widths there are, but an AST
+		// could have any
+		let node = |kind| Node {
+			kind,
+			span: Span::default(),
+		};
+		let ast = Ast {
+			nodes: vec![
+				node(NodeKind::Header(Header {
+					bitorder: BitOrder::Msb0,
+					byteorder: ByteOrder::BigEndian,
+					negativekind: NegativeKind::TwosCompliment,
+					pad_bits: false,
+				})),
+				node(NodeKind::Field {
+					kind: FieldKind::Float,
+					width: "8".into(),
+					byteorder: None,
+					value: "1".into(),
+				}),
+			],
+		};
+
+		assert_eq!(
+			Hext::new().lower(&ast).unwrap_err().kind(),
+			&ErrorKind::InvalidFloatWidth("8".into())
+		);
+	}
+
+	#[test]
+	fn ftest_float_invalid() {
+		for value in ["abc", "nan(0)", "nan(1024)", "-+1", "NaN", ""] {
+			let test = format!("~big-endian msb0\nf16={}", value);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidFloat(value.into())
+			);
+		}
+	}
+
+	//## Radix Tests ##
+	#[test]
+	fn radix_hex_little_endian() {
+		let test = "~little-endian msb0\nu32=0xDEADBEEF";
+		let cmp = vec![0xEF, 0xBE, 0xAD, 0xDE];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn radix_octal_and_binary() {
+		let test = "~big-endian msb0\nu16=0o755 u8=0b1010_0101";
+		let cmp = vec![0x01, 0xED, 0xA5];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn radix_signed() {
+		let test = "~big-endian msb0\ni16=-0x8000 i8=+0b1";
+		let cmp = vec![0x80, 0x00, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn radix_unsigned_plus() {
+		let test = "~big-endian msb0\nu8=+5 u16=+0x10";
+		let cmp = vec![0x05, 0x00, 0x10];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn radix_upper_case() {
+		let test = "~big-endian msb0\nu8=0XFF u16=0O755 u8=0B1010_0101";
+		let cmp = vec![0xFF, 0x01, 0xED, 0xA5];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn radix_separators() {
+		let test = "~big-endian msb0\nu32=1_000_000 =0x1_0000";
+		let cmp = vec![0x00, 0x0F, 0x42, 0x40, 0x00, 0x01, 0x00, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn radix_nan_payload() {
+		let test = "~big-endian msb0\nf16=nan(0x3FF)";
+		let cmp = vec![0x7F, 0xFF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_radix_invalid() {
+		for value in ["0x", "0x_1", "0b102", "0xFFF", "0o-1"] {
+			let test = format!("~big-endian msb0\nu8={}", value);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidUnsignedDecimal(value.into())
+			);
+		}
+	}
+
+	//## Byte Order Override Tests ##
+	#[test]
+	fn byteorder_suffix() {
+		let test = "~little-endian msb0\nu32be=1 u16le=7 u16=7";
+		let cmp = vec![0x00, 0x00, 0x00, 0x01, 0x07, 0x00, 0x07, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn byteorder_suffix_signed_and_float() {
+		let test = "~big-endian msb0\ni16le=-2 f32le=1";
+		let cmp = vec![0xFE, 0xFF, 0x00, 0x00, 0x80, 0x3F];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn byteorder_directive() {
+		let test =
+			"~little-endian msb0\nu16=1 =256\n~big-endian\nu16=1 =256\n~little-endian\nu16=1";
+		let cmp = vec![0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x01, 0x00, 0x01, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn byteorder_directive_with_comment_and_suffix() {
+		let test = "~little-endian msb0\n# Network order from here on\n~big-endian\nu16le=1 u16=1";
+		let cmp = vec![0x01, 0x00, 0x00, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_byteorder_directive_header_only() {
+		let test = "~little-endian msb0\n~big-endian lsb0";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::HeaderOnly("lsb0".into()))
+		);
+	}
+
+	#[test]
+	fn ftest_byteorder_directive_empty() {
+		let test = "~little-endian msb0\n~\n41";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidHeader(InvalidHeaderKind::NoByteOrder)
+		);
+	}
+
+	#[test]
+	fn ftest_byteorder_suffix_invalid() {
+		let test = "~little-endian msb0\nu16xe=1";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidBitness("16xe".into())
+		);
+	}
+
+	//## Label Tests ##
+	#[test]
+	fn label_forward_reference() {
+		let test = "~little-endian msb0\nu16=@data 00\n@data\n\"hi\"";
+		let cmp = vec![0x03, 0x00, 0x00, b'h', b'i'];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn label_backward_reference() {
+		let test = "~big-endian msb0\n00 @here 00 u32=@here";
+		let cmp = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn label_difference() {
+		let test = "~big-endian msb0\nu8=@end-@start+1 @start \"abc\" @end";
+		let cmp = vec![0x04, b'a', b'b', b'c'];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn label_signed_difference() {
+		let test = "~big-endian msb0 sign-magnitude\n@start 00 i8=@start-@end @end";
+		let cmp = vec![0x00, 0x82];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn label_reference_in_bitstream() {
+		let test = "~big-endian msb0 padbits\n.1 u12=@end .111 @end";
+		let cmp = vec![0x80, 0x17];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+
+		// The padding on the front of a partial octet moves the field along
+		let test = "~big-endian msb0 padbits\n.1 u3=@end 41 @end";
+		let cmp = vec![0x0A, 0x41];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn label_reference_lsb0() {
+		let test = "~little-endian lsb0\nu4=@end .1111 00 @end";
+		let cmp = vec![0xF2, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn label_after_field() {
+		let test = "~big-endian msb0\nu16=1 @after u8=@after";
+		let cmp = vec![0x00, 0x01, 0x02];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_label_undefined() {
+		let test = "~big-endian msb0\nu8=@nowhere";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UndefinedLabel("nowhere".into())
+		);
+	}
+
+	#[test]
+	fn ftest_label_duplicate() {
+		let test = "~big-endian msb0\n@a 00 @a";
+		let error = Hext::new().parse(test).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::DuplicateLabel("a".into()));
+		assert_eq!(&test[error.span().range()], "@a");
+		assert_eq!(error.span().start, 23);
+	}
+
+	#[test]
+	fn ftest_label_unaligned() {
+		let test = "~big-endian msb0\n.1 @a .0000000";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnalignedLabel("a".into())
+		);
+	}
+
+	#[test]
+	fn ftest_label_does_not_fit() {
+		let test = "~big-endian msb0\nu8=@start-@end @start 00 @end";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::DoesNotFit {
+				expression: "@start-@end".into(),
+				value: -1,
+				width: 8
+			}
+		);
+	}
+
+	#[test]
+	fn ftest_label_invalid() {
+		let test = "~big-endian msb0\n@1abc";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidLabel("1abc".into())
+		);
+
+		let test = "~big-endian msb0\nu8=@a+ @a";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidExpression("@a+".into())
+		);
+	}
+
+	//## Block Tests ##
+	#[test]
+	fn block_length_prefix() {
+		let test = "~big-endian msb0\nu8{ \"abc\" } 00";
+		let cmp = vec![0x03, b'a', b'b', b'c', 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_length_adjusted() {
+		let test = "~little-endian msb0\nu16+2{01 02} u16be-1{ 01 02 }";
+		let cmp = vec![0x04, 0x00, 0x01, 0x02, 0x00, 0x01, 0x01, 0x02];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_nested() {
+		let test = "~big-endian msb0\nu8{ 01 u8{ 02 03 } }";
+		let cmp = vec![0x04, 0x01, 0x02, 0x02, 0x03];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_named_length_elsewhere() {
+		// A PNG chunk, where the length doesn't cover the chunk type
+		let test = "~big-endian msb0\nu32=@data.end-@data \"IHDR\" @data{ 00 01 02 }";
+		let cmp = vec![
+			0x00, 0x00, 0x00, 0x03, b'I', b'H', b'D', b'R', 0x00, 0x01, 0x02,
+		];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_varint() {
+		let test = "~big-endian msb0\nvarint{ 01 02 } varint+1{}";
+		let cmp = vec![0x02, 0x01, 0x02, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_varint_multibyte_moves_contents() {
+		let literal = "a".repeat(200);
+		let test = format!(
+			"~big-endian msb0\nu8=@inner varint{{ @inner \"{}\" u8=@inner }} u8=@inner",
+			literal
+		);
+
+		let mut cmp = vec![0x03, 0xC9, 0x01];
+		cmp.extend_from_slice(literal.as_bytes());
+		cmp.extend_from_slice(&[0x03, 0x03]);
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_length_around_varint() {
+		let literal = "a".repeat(128);
+		let test = format!("~big-endian msb0\nu16{{ varint{{ \"{}\" }} }}", literal);
+
+		let mut cmp = vec![0x00, 0x82, 0x80, 0x01];
+		cmp.extend_from_slice(literal.as_bytes());
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn block_plain() {
+		let test = "~big-endian msb0\n{ 01 { .0000 } .0010 }";
+		let cmp = vec![0x01, 0x02];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_block_unclosed() {
+		let test = "~big-endian msb0\nu8{ 01 { 02 }";
+		let error = Hext::new().parse(test).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::UnclosedBlock);
+		assert_eq!(&test[error.span().range()], "u8{");
+	}
+
+	#[test]
+	fn ftest_block_unmatched() {
+		let test = "~big-endian msb0\n01 }";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnmatchedBlockClose
+		);
+	}
+
+	#[test]
+	fn ftest_block_too_long() {
+		let test = format!("~big-endian msb0\nu8{{ \"{}\" }}", "a".repeat(256));
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::BlockLengthDoesNotFit(256)
+		);
+
+		let test = "~big-endian msb0\nvarint-2{ 01 }";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::BlockLengthDoesNotFit(-1)
+		);
+	}
+
+	#[test]
+	fn ftest_block_invalid() {
+		for spec in ["u8+{", "u8+?{", "varnit{"] {
+			let test = format!("~big-endian msb0\n{} 01 }}", spec);
+			let name = spec.trim_end_matches('{');
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidBlock(name.into())
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_block_unaligned() {
+		let test = "~big-endian msb0\nu8{ .1 }";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnalignedBlock
+		);
+	}
+
+	//## Checksum Tests ##
+	#[test]
+	fn checksum_check_values() {
+		let checks: [(&str, &[u8]); 12] = [
+			("crc8", &[0xF4]),
+			("crc8-maxim", &[0xA1]),
+			("crc16", &[0xBB, 0x3D]),
+			("crc16-ccitt", &[0x29, 0xB1]),
+			("crc16-xmodem", &[0x31, 0xC3]),
+			("crc16-modbus", &[0x4B, 0x37]),
+			("crc32", &[0xCB, 0xF4, 0x39, 0x26]),
+			("crc32c", &[0xE3, 0x06, 0x92, 0x83]),
+			("crc32-bzip2", &[0xFC, 0x89, 0x19, 0x18]),
+			("crc32-mpeg2", &[0x03, 0x76, 0xE6, 0xE7]),
+			("crc64", &[0x6C, 0x40, 0xDF, 0x5F, 0x0B, 0x49, 0x73, 0x47]),
+			(
+				"crc64-xz",
+				&[0x99, 0x5D, 0xC9, 0xBB, 0xDF, 0x19, 0x39, 0xFA],
+			),
+		];
+
+		for (name, check) in checks {
+			let test = format!(
+				"~big-endian msb0\n@data{{ \"123456789\" }} !{}(@data)",
+				name
+			);
+			let mut cmp = b"123456789".to_vec();
+			cmp.extend_from_slice(check);
+
+			assert_eq!(Hext::new().parse(test).unwrap(), cmp, "{}", name);
+		}
+	}
+
+	#[test]
+	fn checksum_sums() {
+		let checks: [(&str, &str, &[u8]); 4] = [
+			("adler32", "Wikipedia", &[0x11, 0xE6, 0x03, 0x98]),
+			("fletcher16", "abcde", &[0xC8, 0xF0]),
+			("fletcher32", "abcde", &[0xF0, 0x4F, 0xC7, 0x29]),
+			("fletcher32", "abcdef", &[0x56, 0x50, 0x2D, 0x2A]),
+		];
+
+		for (name, data, check) in checks {
+			let test = format!("~big-endian msb0\n@data{{ \"{}\" }} !{}(@data)", data, name);
+			let mut cmp = data.as_bytes().to_vec();
+			cmp.extend_from_slice(check);
+
+			assert_eq!(Hext::new().parse(test).unwrap(), cmp, "{}", name);
+		}
+	}
+
+	#[test]
+	fn checksum_internet_covers_itself() {
+		let test = "~big-endian msb0
+			@ip{
+				45 00 0073 0000 4000 40 11
+				!internet(@ip)
+				c0a80001 c0a800c7
+			}";
+		let cmp = vec![
+			0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xB8, 0x61, 0xC0, 0xA8,
+			0x00, 0x01, 0xC0, 0xA8, 0x00, 0xC7,
+		];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn checksum_internet_large() {
+		// Enough words that their plain sum wouldn't fit in 32 bits
+		let test = "~big-endian msb0\n@data{ FFFF * 70000 0102 } !internet(@data)";
+		let mut cmp = vec![0xFF; 140000];
+		cmp.extend_from_slice(&[0x01, 0x02, 0xFE, 0xFD]);
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn checksum_between_labels() {
+		let test = "~big-endian msb0\n!crc32(@start, @end) @start \"IEND\" @end";
+		let cmp = vec![0xAE, 0x42, 0x60, 0x82, 0x49, 0x45, 0x4E, 0x44];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn checksum_byteorder() {
+		let test = "~little-endian msb0\n@data{ \"IEND\" } !crc32(@data)";
+		let cmp = vec![0x49, 0x45, 0x4E, 0x44, 0x82, 0x60, 0x42, 0xAE];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn checksum_covers_checksum() {
+		let test = "~big-endian msb0\n@outer{ 02 @inner{ 01 } !crc8(@inner) } !crc8(@outer)";
+		let cmp = vec![0x02, 0x01, 0x07, 0xD6];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn checksum_covers_labeled_fields() {
+		let test = "~big-endian msb0\n@data{ u8=@data.end } !crc8(@data)";
+		let cmp = vec![0x01, 0x07];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn checksum_in_bitstream() {
+		let test = "~big-endian msb0\n@data{ \"AB\" } .1010 !crc8(@data) .1010";
+		let cmp = vec![0x41, 0x42, 0xA8, 0x7A];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_checksum_cycle() {
+		let test = "~big-endian msb0\n@a{ !crc8(@b) } @b{ !crc8(@a) }";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::ChecksumCycle
+		);
+	}
+
+	#[test]
+	fn ftest_checksum_backwards_region() {
+		let test = "~big-endian msb0\n@a 01 @b !crc8(@b, @a)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::BackwardsRegion
+		);
+	}
+
+	#[test]
+	fn ftest_checksum_undefined_label() {
+		let test = "~big-endian msb0\n@a{ 01 } !crc8(@a, @b)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UndefinedLabel("b".into())
+		);
+	}
+
+	#[test]
+	fn ftest_checksum_invalid_arguments() {
+		for arguments in ["", "a", "@a, @b, @c", "@1"] {
+			let test = format!("~big-endian msb0\n@a 01 !crc8({})", arguments);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidArguments {
+					directive: "crc8".into(),
+					arguments: arguments.into()
+				}
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_directive_unknown() {
+		let test = "~big-endian msb0\n@a 01 !crc33(@a)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnknownDirective("crc33".into())
+		);
+	}
+
+	#[test]
+	fn ftest_directive_unclosed() {
+		let test = "~big-endian msb0\n@a 01 !crc8(@a\n)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnclosedDirective
+		);
+	}
+
+	//## Digest Tests ##
+	#[test]
+	#[cfg(feature = "digest")]
+	fn digest_check_values() {
+		let checks = [
+			("md5", "900150983cd24fb0d6963f7d28e17f72"),
+			("sha1", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+			(
+				"sha256",
+				"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+			),
+			(
+				"blake3",
+				"6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+			),
+		];
+
+		for (name, digest) in checks {
+			let test = format!("~little-endian msb0\n@data{{ \"abc\" }} !{}(@data)", name);
+			let cmp = format!("~little-endian msb0\n\"abc\" {}", digest);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap(),
+				Hext::new().parse(cmp).unwrap(),
+				"{}",
+				name
+			);
+		}
+	}
+
+	#[test]
+	#[cfg(feature = "digest")]
+	fn digest_covered_by_checksum() {
+		let test = "~big-endian msb0\n@all{ @data{ \"abc\" } !md5(@data) } !crc32(@all)";
+		let cmp = Hext::new()
+			.parse("~big-endian msb0\n\"abc\" 900150983cd24fb0d6963f7d28e17f72")
+			.unwrap();
+		let parsed = Hext::new().parse(test).unwrap();
+
+		assert_eq!(parsed[..19], cmp[..]);
+		assert_eq!(parsed.len(), 23);
+	}
+
+	#[test]
+	#[cfg(not(feature = "digest"))]
+	fn ftest_digest_unavailable() {
+		let test = "~big-endian msb0\n@data{ 01 } !sha256(@data)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::DigestUnavailable("sha256".into())
+		);
+	}
+
+	//## Include Tests ##
+	/// Includes files from a list of names and contents
+	struct ListResolver(&'static [(&'static str, &'static str)]);
+
+	impl Resolver for ListResolver {
+		fn resolve(
+			&mut self,
+			path: &str,
+			_from: Option<&str>,
+		) -> std::io::Result<(String, String)> {
+			self.0
+				.iter()
+				.find(|(name, _)| *name == path)
+				.map(|(name, contents)| (name.to_string(), contents.to_string()))
+				.ok_or_else(|| std::io::ErrorKind::NotFound.into())
+		}
+
+		fn resolve_bytes(&mut self, path: &str, from: Option<&str>) -> std::io::Result<Vec<u8>> {
+			self.resolve(path, from)
+				.map(|(_, contents)| contents.into_bytes())
+		}
+	}
+
+	fn include_parse(
+		test: &str,
+		files: &'static [(&'static str, &'static str)],
+	) -> Result<Vec<u8>, Error> {
+		Hext::new()
+			.name("main.hxt")
+			.resolver(ListResolver(files))
+			.parse(test)
+	}
+
+	#[test]
+	fn include_splices() {
+		let test = "~big-endian msb0\n01 !include(\"mid.hxt\") 04";
+		let cmp = vec![0x01, 0x02, 0x03, 0x04];
+
+		assert_eq!(include_parse(test, &[("mid.hxt", "02 03")]).unwrap(), cmp);
+	}
+
+	#[test]
+	fn include_nested_with_labels() {
+		let test = "~big-endian msb0\n@main u8=@inner !include(\"outer.hxt\")";
+		let files = &[
+			("outer.hxt", "01 !include(\"inner.hxt\") u8=@main"),
+			("inner.hxt", "@inner 02"),
+		];
+		let cmp = vec![0x02, 0x01, 0x02, 0x00];
+
+		assert_eq!(include_parse(test, files).unwrap(), cmp);
+	}
+
+	#[test]
+	fn include_byteorder_does_not_leak() {
+		let test = "~little-endian msb0\n!include(\"be.hxt\") u16=1";
+		let files = &[("be.hxt", "~big-endian\nu16=1")];
+		let cmp = vec![0x00, 0x01, 0x01, 0x00];
+
+		assert_eq!(include_parse(test, files).unwrap(), cmp);
+	}
+
+	#[test]
+	fn include_bits_continue() {
+		let test = "~big-endian msb0\n.1010 !include(\"bits.hxt\")";
+		let cmp = vec![0xA5];
+
+		assert_eq!(include_parse(test, &[("bits.hxt", ".0101")]).unwrap(), cmp);
+	}
+
+	#[test]
+	fn include_from_files() {
+		let test = std::fs::read_to_string("tests/include/packet.hxt").unwrap();
+		let cmp = vec![
+			0x04, 0xD2, 0x00, 0x35, 0x00, 0x0D, 0x00, 0x00, 0x68, 0x65, 0x6C, 0x6C, 0x6F,
+		];
+
+		assert_eq!(
+			Hext::new()
+				.name("tests/include/packet.hxt")
+				.parse(test)
+				.unwrap(),
+			cmp
+		);
+	}
+
+	#[test]
+	fn ftest_include_error_location() {
+		let test = "~big-endian msb0\n01\n!include(\"outer.hxt\")";
+		let files = &[
+			("outer.hxt", "02 !include(\"inner.hxt\")"),
+			("inner.hxt", "03\n  G"),
+		];
+		let error = include_parse(test, files).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::InvalidCharacter('G'));
+		assert_eq!(error.file(), Some("inner.hxt"));
+		assert_eq!((error.span().line, error.span().column), (2, 3));
+
+		let includes: Vec<_> = error
+			.includes()
+			.iter()
+			.map(|include| {
+				(
+					include.file.as_deref(),
+					include.span.line,
+					include.span.column,
+				)
+			})
+			.collect();
+		assert_eq!(
+			includes,
+			[(Some("main.hxt"), 3, 1), (Some("outer.hxt"), 1, 4)]
+		);
+	}
+
+	#[test]
+	fn ftest_include_self_named_differently() {
+		let directory = std::env::temp_dir().join(format!("hext-include-{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		std::fs::write(
+			directory.join("a.hxt"),
+			"~big-endian msb0\n!include(\"a.hxt\")",
+		)
+		.unwrap();
+
+		let name = directory.join(".").join("a.hxt").display().to_string();
+		let test = std::fs::read_to_string(&name).unwrap();
+		let error = Hext::new().name(name).parse(test).unwrap_err();
+		std::fs::remove_dir_all(&directory).unwrap();
+
+		assert!(matches!(error.kind(), ErrorKind::IncludeCycle(_)));
+		assert_eq!(error.includes().len(), 0);
+	}
+
+	#[test]
+	fn ftest_include_fixup_error_location() {
+		let test = "~big-endian msb0\n!include(\"label.hxt\")";
+		let error = include_parse(test, &[("label.hxt", "\n u8=@nowhere")]).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::UndefinedLabel("nowhere".into()));
+		assert_eq!(error.file(), Some("label.hxt"));
+		assert_eq!(error.span().line, 2);
+	}
+
+	#[test]
+	fn ftest_include_cycle() {
+		let test = "~big-endian msb0\n!include(\"a.hxt\")";
+		let files = &[
+			("a.hxt", "!include(\"b.hxt\")"),
+			("b.hxt", "!include(\"a.hxt\")"),
+		];
+		let error = include_parse(test, files).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::IncludeCycle("a.hxt".into()));
+		assert_eq!(error.file(), Some("b.hxt"));
+		assert_eq!(error.includes().len(), 2);
+	}
+
+	#[test]
+	fn ftest_include_missing() {
+		let test = "~big-endian msb0\n!include(\"nope.hxt\")";
+
+		assert!(matches!(
+			include_parse(test, &[]).unwrap_err().kind(),
+			ErrorKind::IncludeFailed { path, .. } if path == "nope.hxt"
+		));
+	}
+
+	#[test]
+	fn ftest_include_blocks_stay_inside() {
+		let test = "~big-endian msb0\n!include(\"open.hxt\") }";
+
+		assert_eq!(
+			include_parse(test, &[("open.hxt", "u8{ 01")])
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::UnclosedBlock
+		);
+
+		let test = "~big-endian msb0\nu8{ !include(\"close.hxt\")";
+
+		assert_eq!(
+			include_parse(test, &[("close.hxt", "01 }")])
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::UnmatchedBlockClose
+		);
+	}
+
+	#[test]
+	fn ftest_include_invalid_arguments() {
+		let test = "~big-endian msb0\n!include(a.hxt)";
+
+		assert_eq!(
+			include_parse(test, &[]).unwrap_err().kind(),
+			&ErrorKind::InvalidArguments {
+				directive: "include".into(),
+				arguments: "a.hxt".into()
+			}
+		);
+	}
+
+	//## Embed Tests ##
+	#[test]
+	fn embed_whole() {
+		let test = "~big-endian msb0\n01 !embed(\"blob.bin\") 02";
+		let cmp = b"\x01blob\x02".to_vec();
+
+		assert_eq!(include_parse(test, &[("blob.bin", "blob")]).unwrap(), cmp);
+	}
+
+	#[test]
+	fn embed_slice() {
+		let files = &[("blob.bin", "0123456789")];
+
+		let test = "~big-endian msb0\n!embed(\"blob.bin\", 7)";
+		assert_eq!(include_parse(test, files).unwrap(), b"789".to_vec());
+
+		let test = "~big-endian msb0\n!embed(\"blob.bin\", 0x2, 3)";
+		assert_eq!(include_parse(test, files).unwrap(), b"234".to_vec());
+
+		let test = "~big-endian msb0\n!embed( \"blob.bin\" , 10 , 0 )";
+		assert_eq!(include_parse(test, files).unwrap(), vec![]);
+	}
+
+	#[test]
+	fn embed_labels_around() {
+		let test = "~big-endian msb0\nu8{ !embed(\"blob.bin\") }";
+		let cmp = b"\x04blob".to_vec();
+
+		assert_eq!(include_parse(test, &[("blob.bin", "blob")]).unwrap(), cmp);
+	}
+
+	#[test]
+	fn embed_from_files() {
+		let test = "~big-endian msb0\n!embed(\"../everything.correct\", 0, 8)";
+		let cmp = std::fs::read("tests/everything.correct").unwrap();
+
+		assert_eq!(
+			Hext::new()
+				.name("tests/include/embed.hxt")
+				.parse(test)
+				.unwrap(),
+			cmp[..8]
+		);
+	}
+
+	#[test]
+	fn ftest_embed_out_of_range() {
+		let files = &[("blob.bin", "0123456789")];
+
+		for slice in ["11", "8, 3", "0, 0xFFFFFFFFFFFFFFFF"] {
+			let test = format!("~big-endian msb0\n!embed(\"blob.bin\", {})", slice);
+
+			assert_eq!(
+				include_parse(&test, files).unwrap_err().kind(),
+				&ErrorKind::EmbedOutOfRange(10)
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_embed_invalid_arguments() {
+		for arguments in [
+			"blob.bin",
+			"\"blob.bin\" 1",
+			"\"blob.bin\", ?",
+			"\"blob.bin\", 1, 2, 3",
+		] {
+			let test = format!("~big-endian msb0\n!embed({})", arguments);
+
+			assert_eq!(
+				include_parse(&test, &[]).unwrap_err().kind(),
+				&ErrorKind::InvalidArguments {
+					directive: "embed".into(),
+					arguments: arguments.into()
+				}
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_embed_unaligned() {
+		let test = "~big-endian msb0\n.101 !embed(\"blob.bin\")";
+
+		assert_eq!(
+			include_parse(test, &[("blob.bin", "blob")])
+				.unwrap_err()
+				.kind(),
+			&ErrorKind::UnalignedBits
+		);
+	}
+
+	//## Repeat Tests ##
+	#[test]
+	fn repeat_octet() {
+		let test = "~big-endian msb0\n00 * 4 ff";
+		let cmp = vec![0x00, 0x00, 0x00, 0x00, 0xFF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_octet_run() {
+		let test = "~big-endian msb0\n01 DEADBEEF*2";
+		let cmp = vec![0x01, 0xDE, 0xAD, 0xBE, 0xEF, 0xDE, 0xAD, 0xBE, 0xEF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_only_last_octet() {
+		let test = "~big-endian msb0\n01 02 * 3";
+		let cmp = vec![0x01, 0x02, 0x02, 0x02];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_tokens() {
+		let test = "~big-endian msb0\n\"ab\" * 2 u16=0x102 * 2 =-1 * 0x2 f16=1 * 2";
+		let cmp = vec![
+			0x61, 0x62, 0x61, 0x62, 0x01, 0x02, 0x01, 0x02, 0xFF, 0xFF, 0x3C, 0x00, 0x3C, 0x00,
+		];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_bits() {
+		let test = "~big-endian msb0\n.1 .10 * 3 .1";
+		let cmp = vec![0xD5];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_group() {
+		let test = "~big-endian msb0
+			{
+				u4=0xA .0101 # Bits
+				\"x\"
+			} * 2";
+		let cmp = vec![0xA5, 0x78, 0xA5, 0x78];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_nested() {
+		let test = "~big-endian msb0\n{ 00 * 2 01 } * 2";
+		let cmp = vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_length_block() {
+		let test = "~big-endian msb0\nu8{ 00 * 3 } * 2";
+		let cmp = vec![0x03, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn repeat_with_labels_outside() {
+		let test = "~big-endian msb0\nu8=@end-@start @start 00 * 300 @end";
+		let parsed = Hext::new().parse(test);
+
+		assert_eq!(
+			parsed.unwrap_err().kind(),
+			&ErrorKind::DoesNotFit {
+				expression: "@end-@start".into(),
+				value: 300,
+				width: 8
+			}
+		);
+
+		let test = "~big-endian msb0\nu16=@end-@start @start 00 * 300 @end";
+		assert_eq!(Hext::new().parse(test).unwrap()[..2], [0x01, 0x2C]);
+	}
+
+	#[test]
+	fn ftest_repeat_nothing() {
+		for test in ["* 2", "@a * 2", "{ * 2 }", "u8{ * 2 }", "00 * 2 * 2"] {
+			let test = format!("~big-endian msb0\n{}", test);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::NothingToRepeat
+			);
+		}
+	}
+
+	#[test]
+	fn repeat_none() {
+		// Padding out to a size that's already been reached
+		let test = "~big-endian msb0\n$LEN = 4\n01 02 03 04 00 * (4 - LEN) # Nothing\n05 * 0 06";
+		let cmp = vec![0x01, 0x02, 0x03, 0x04, 0x06];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_repeat_invalid_count() {
+		for count in ["?", "-1", ""] {
+			let test = format!("~big-endian msb0\n00 * {}", count);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidRepeat(count.into())
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_repeat_count_on_next_line() {
+		let test = "~big-endian msb0\n00 *\n4";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidRepeat("".into())
+		);
+	}
+
+	#[test]
+	fn ftest_repeat_label_in_group() {
+		let test = "~big-endian msb0\n{ @a 00 } * 2";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::DuplicateLabel("a".into())
+		);
+	}
+
+	#[test]
+	fn span_in_repeat() {
+		let test = "~big-endian msb0\n{ .1 } * 3 00";
+		let span = error_span(test);
+
+		assert_eq!(&test[span.range()], ".1");
+	}
+
+	//## Padding Tests ##
+	#[test]
+	fn align_to_boundary() {
+		let test =
+			"~big-endian msb0\n01 02 03 !align(4) 04 !align(4) !align(2, 0xFF) 05 !align(4,0xFF)";
+		let cmp = vec![
+			0x01, 0x02, 0x03, 0x00, 0x04, 0x00, 0x00, 0x00, 0x05, 0xFF, 0xFF, 0xFF,
+		];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn align_flushes_bits() {
+		let test = "~big-endian msb0 padbits\n.1 !align(2)";
+		let cmp = vec![0x01, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn pad_to_offset() {
+		let test = "~big-endian msb0\n\"ab\" !pad(4) 01 !pad(0x6, 0xEE) !pad(6)";
+		let cmp = vec![0x61, 0x62, 0x00, 0x00, 0x01, 0xEE];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn pad_in_length_block() {
+		let test = "~big-endian msb0\nu8{ 01 !align(4) }";
+		let cmp = vec![0x03, 0x01, 0x00, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_pad_past() {
+		let test = "~big-endian msb0\n01 02 03 !pad(2)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::PaddedPast {
+				offset: 2,
+				length: 3
+			}
+		);
+	}
+
+	#[test]
+	fn ftest_pad_in_varint_block() {
+		let test = "~big-endian msb0\nvarint{ 01 !align(4) }";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::PadInVarintBlock
+		);
+	}
+
+	#[test]
+	fn ftest_pad_invalid_arguments() {
+		for (directive, arguments) in [
+			("align", "0"),
+			("align", ""),
+			("align", "4, 256"),
+			("pad", "1, 2, 3"),
+			("pad", "?"),
+		] {
+			let test = format!("~big-endian msb0\n!{}({})", directive, arguments);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidArguments {
+					directive: directive.into(),
+					arguments: arguments.into()
+				}
+			);
+		}
+	}
+
+	//## Constant Tests ##
+	#[test]
+	fn constant_in_decimals() {
+		let test = "~big-endian msb0
+			$WIDTH = 4
+			$HEIGHT=0x10 # Comments are fine
+			u16=$WIDTH u8=$HEIGHT =$WIDTH i8=-$WIDTH";
+		let cmp = vec![0x00, 0x04, 0x10, 0x04, 0xFC];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn constant_negative() {
+		let test = "~big-endian msb0\n$D = -2\ni8=$D i8=-$D @a u8=@b+$D 00 00 00 @b";
+		let cmp = vec![0xFE, 0x02, 0x04, 0x00, 0x00, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn constant_from_constant() {
+		let test = "~big-endian msb0\n$A = 3\n$B = $A\nu8=$B";
+		let cmp = vec![0x03];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn constant_in_counts_and_padding() {
+		let test = "~big-endian msb0
+			$COUNT = 3
+			$FILL = 0xFF
+			01 * $COUNT !align($COUNT) u8{ 02 } !pad(8, $FILL) varint+$COUNT{ }";
+		let cmp = vec![0x01, 0x01, 0x01, 0x01, 0x02, 0xFF, 0xFF, 0xFF, 0x03];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn constant_not_in_strings() {
+		let test = "~big-endian msb0\n\"$A\"";
+		let cmp = b"$A".to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_constant_undefined() {
+		let test = "~big-endian msb0\nu8=$NOPE\n$NOPE = 1";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UndefinedConstant("NOPE".into())
+		);
+	}
+
+	#[test]
+	fn ftest_constant_duplicate() {
+		let test = "~big-endian msb0\n$A = 1\n$A = 2";
+		let error = Hext::new().parse(test).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::DuplicateConstant("A".into()));
+		assert_eq!(&test[error.span().range()], "$A = 2");
+	}
+
+	#[test]
+	fn ftest_constant_invalid() {
+		for (definition, cmp) in [
+			("$A 1", "$A 1"),
+			("$1A = 1", "1A"),
+			("$A = ?", "?"),
+			("$A = 1 2", "1 2"),
+		] {
+			let test = format!("~big-endian msb0\n{}", definition);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidConstant(cmp.into())
+			);
+		}
+	}
+
+	//## Expression Tests ##
+	#[test]
+	fn expression_constants() {
+		let test = "~big-endian msb0\n$HEADER_LEN = 8\n$COUNT = 3\nu32=(HEADER_LEN + 4*COUNT)";
+		let cmp = vec![0x00, 0x00, 0x00, 0x14];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_precedence() {
+		let test = "~big-endian msb0\nu8=(1<<3)|2 u8=1+2*3 u8=(1+2)*3 u8=7-2-1 u8=6&3^1 i8=-(2*3)";
+		let cmp = vec![0x0A, 0x07, 0x09, 0x04, 0x03, 0xFA];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_operators() {
+		let test = "~big-endian msb0\n$A = 4\nu8=0x100>>4 u8=17/5 u8=17%5 u8=~0&0xF u8=$A|0b1";
+		let cmp = vec![0x10, 0x03, 0x02, 0x0F, 0x05];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_unsized() {
+		let test = "~big-endian msb0\n$A = 0x80\n=(A*2) =(0-A-1)";
+		let cmp = vec![0x01, 0x00, 0xFF, 0x7F];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_in_constant() {
+		let test = "~big-endian msb0\n$A = 3\n$B = (A * 2) + 1\nu8=B";
+		let cmp = vec![0x07];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_in_block_and_directive() {
+		let test = "~big-endian msb0\n$EXTRA = 2\nu8+(EXTRA*2){ 01 } !pad(EXTRA+3, 0xFF)";
+		let cmp = vec![0x05, 0x01, 0xFF, 0xFF, 0xFF];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_repeat() {
+		let test = "~big-endian msb0\n$N = 2\nAA * (N+1)";
+		let cmp = vec![0xAA, 0xAA, 0xAA];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn expression_with_labels() {
+		let test = "~big-endian msb0\n$HEADER = 2\nu8=((@end - @start) / 2 + HEADER) @start 01 02 03 04 @end";
+		let cmp = vec![0x04, 0x01, 0x02, 0x03, 0x04];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_expression_overflow() {
+		for value in [
+			"u128=(1<<126)*4",
+			"u64=1<<200",
+			"u8=3<<127",
+			"u128=340282366920938463463374607431768211455+0",
+			"i8=170141183460469231731687303715884105727+1",
+		] {
+			let test = format!("~big-endian msb0\n{}", value);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::ExpressionOverflow
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_expression_does_not_fit() {
+		let test = "~big-endian msb0\nu8=(16*16)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::DoesNotFit {
+				expression: "(16*16)".into(),
+				value: 256,
+				width: 8
+			}
+		);
+	}
+
+	#[test]
+	fn ftest_expression_division_by_zero() {
+		for value in ["u8=1/0", "u8=1%(2-2)"] {
+			let test = format!("~big-endian msb0\n{}", value);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::DivisionByZero
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_expression_label_not_allowed() {
+		let test = "~big-endian msb0\n@a =(@a+1)";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::LabelNotAllowed("a".into())
+		);
+	}
+
+	#[test]
+	fn ftest_expression_invalid() {
+		for value in ["(@a+2", "@a+*2", "(@a+)"] {
+			let test = format!("~big-endian msb0\n@a u8={}", value);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidExpression(value.into())
+			);
+		}
+	}
+
+	//## Macro Tests ##
+	#[test]
+	fn macro_tlv() {
+		let test = "~big-endian msb0
+!macro tlv(type, value)
+	u8=$type u8{ $value } # the $type is not replaced in comments
+!end
+
+!tlv(1, \"hi\")
+!tlv(2, 0102 03)";
+		let cmp = vec![0x01, 0x02, b'h', b'i', 0x02, 0x03, 0x01, 0x02, 0x03];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_argument_precedence() {
+		let test = "~big-endian msb0
+!macro dbl(x)
+u8=$x*2 u8=(10 - $x)
+!end
+!dbl(1+2)";
+		let cmp = vec![0x06, 0x07];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_argument_repeat() {
+		let test = "~big-endian msb0
+!macro fill(x)
+$x
+!end
+!fill(00 * 4)";
+		let cmp = vec![0x00, 0x00, 0x00, 0x00];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_argument_precedence_in_constant() {
+		let test = "~big-endian msb0
+!macro scaled(x)
+$SCALED = $x * 2
+!if($x * 2 == 6)
+	u8=SCALED
+!end
+!end
+!scaled(1 + 2)";
+		let cmp = vec![0x06];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_no_parameters() {
+		let test = "~big-endian msb0\n!macro magic\n\"GIF\"\n!end\n!magic !magic()";
+		let cmp = b"GIFGIF".to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_arguments() {
+		let test = "~big-endian msb0
+!macro pair(a, b)
+$a u8=$b \"$a\"
+!end
+!pair(\"x,)\", (1 + 1))";
+		let cmp = b"x,)\x02$a".to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_nested() {
+		let test = "~big-endian msb0
+$A = 4
+!macro byte(value)
+u8=$value
+!end
+!macro rr(type, length)
+!byte($type) !byte(($length * 2))
+!end
+!rr(1, 2) !rr(3, A)";
+		let cmp = vec![0x01, 0x04, 0x03, 0x08];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_with_blocks_and_repeat() {
+		let test = "~big-endian msb0
+!macro record(value)
+u8{ $value }
+!end
+!record(AA) * 2 !record(u16{ BB })";
+		let cmp = vec![0x01, 0xAA, 0x01, 0xAA, 0x03, 0x00, 0x01, 0xBB];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn macro_from_include() {
+		let test = "~big-endian msb0\n!include(\"macros.hxt\") !two(05)";
+		let files = &[("macros.hxt", "!macro two(a)\n$a $a\n!end")];
+		let cmp = vec![0x05, 0x05];
+
+		assert_eq!(include_parse(test, files).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_macro_error_location() {
+		let test = "~big-endian msb0
+!macro tlv(type, value)
+	u8=$type
+	u8{ $value }
+!end
+00
+!tlv(1, 02)
+!tlv(3, G)";
+		let error = include_parse(test, &[]).unwrap_err();
+
+		// The bad argument is pointed at where its parameter is in the body
+		assert_eq!(error.kind(), &ErrorKind::InvalidCharacter('G'));
+		assert_eq!(error.file(), Some("main.hxt"));
+		assert_eq!((error.span().line, error.span().column), (4, 6));
+		assert_eq!(&test[error.span().range()], "$value");
+
+		let includes: Vec<_> = error
+			.includes()
+			.iter()
+			.map(|include| {
+				(
+					include.macro_name.as_deref(),
+					include.span.line,
+					include.span.column,
+				)
+			})
+			.collect();
+		assert_eq!(includes, [(Some("tlv"), 8, 1)]);
+	}
+
+	#[test]
+	fn ftest_macro_error_in_body() {
+		let test = "~big-endian msb0
+!macro inner(a)
+u8=$a u8=256
+!end
+!macro outer
+	!inner(1)
+!end
+!outer";
+		let error = Hext::new().parse(test).unwrap_err();
+
+		assert_eq!(
+			error.kind(),
+			&ErrorKind::InvalidUnsignedDecimal("256".into())
+		);
+		assert_eq!(&test[error.span().range()], "u8=256");
+		assert_eq!((error.span().line, error.span().column), (3, 7));
+
+		let includes: Vec<_> = error
+			.includes()
+			.iter()
+			.map(|include| (include.macro_name.as_deref(), &test[include.span.range()]))
+			.collect();
+		assert_eq!(
+			includes,
+			[(Some("outer"), "!outer"), (Some("inner"), "!inner(1)")]
+		);
+	}
+
+	#[test]
+	fn ftest_macro_arguments() {
+		for invocation in ["!two(1)", "!two", "!two(1, 2, 3)"] {
+			let test = format!(
+				"~big-endian msb0\n!macro two(a, b)\n$a $b\n!end\n{}",
+				invocation
+			);
+			let found = match invocation {
+				"!two(1)" => 1,
+				"!two" => 0,
+				_ => 3,
+			};
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::MacroArguments {
+					name: "two".into(),
+					expected: 2,
+					found
+				}
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_macro_recursion() {
+		let test = "~big-endian msb0
+!macro a
+!b
+!end
+!macro b
+!a
+!end
+!a";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::MacroRecursion("a".into())
+		);
+	}
+
+	#[test]
+	fn ftest_macro_invalid() {
+		for definition in [
+			"",
+			"two words",
+			"x(a, a)",
+			"x(1)",
+			"x(a",
+			"crc32",
+			"include(a)",
+		] {
+			let test = format!("~big-endian msb0\n!macro {}\n!end", definition);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidMacro(definition.into())
+			);
+		}
+	}
+
+	#[test]
+	fn ftest_macro_duplicate() {
+		let test = "~big-endian msb0\n!macro x\n!end\n!macro x\n!end";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::DuplicateMacro("x".into())
+		);
+	}
+
+	#[test]
+	fn ftest_macro_unclosed() {
+		let test = "~big-endian msb0\n!macro x(a)\n$a # !end\n\"!end\"";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnclosedMacro("x".into())
+		);
+	}
+
+	#[test]
+	fn ftest_macro_unmatched_end() {
+		let test = "~big-endian msb0\n00 !end";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnmatchedEnd
+		);
+	}
+
+	#[test]
+	fn ftest_macro_block_left_open() {
+		let test = "~big-endian msb0\n!macro open\nu8{\n!end\n!open 00 }";
+
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnclosedBlock
+		);
+	}
+
+	//## Conditional Tests ##
+	#[test]
+	fn conditional_branches() {
+		let test = "~big-endian msb0
+$VERSION = 2
+!if(VERSION == 1) 01 !else 02 !end
+!if(VERSION >= 2)
+	AA
+!end
+!if(0) BB !end
+!if(VERSION < 2 || VERSION > 3) CC !else
+	DD
+!end";
+		let cmp = vec![0x02, 0xAA, 0xDD];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn conditional_nested() {
+		let test = "~big-endian msb0
+$A = 1
+!if(A)
+	01
+	!if(!A) 02 !else 03 !end # !else in a comment is ignored
+	\"!end\"
+!else
+	04 !if(1) 05 !end
+!end";
+		let cmp = b"\x01\x03!end".to_vec();
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn conditional_skipped_branch_is_not_parsed() {
+		let test = "~big-endian msb0\n!if(0) not hext at all !end 01";
+		let cmp = vec![0x01];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn conditional_defined() {
+		let test = "~big-endian msb0
+!if(defined(EXTENSION) && EXTENSION != 0)
+	u8{ \"ext\" }
+!end
+00";
+
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![0x00]);
+		assert_eq!(
+			Hext::new().define("EXTENSION", 1).parse(test).unwrap(),
+			b"\x03ext\x00".to_vec()
+		);
+		assert_eq!(
+			Hext::new().define("EXTENSION", 0).parse(test).unwrap(),
+			vec![0x00]
+		);
+	}
+
+	#[test]
+	fn conditional_define_overrides_default() {
+		let test =
+			"~big-endian msb0\n$VERSION = 1\n!if(VERSION == 2) u16=VERSION !else u8=VERSION !end";
+
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![0x01]);
+		assert_eq!(
+			Hext::new().define("VERSION", 2).parse(test).unwrap(),
+			vec![0x00, 0x02]
+		);
+	}
+
+	#[test]
+	fn conditional_define_values() {
+		assert_eq!(Hext::parse_integer("-0x80"), Some(-128));
+		assert_eq!(Hext::parse_integer("+0b1010_0101"), Some(0xA5));
+		assert_eq!(Hext::parse_integer("-+1"), None);
+		assert_eq!(Hext::parse_integer("0x_1"), None);
+		assert!(Hext::is_constant_name("_VERSION2"));
+		assert!(!Hext::is_constant_name("2VERSION"));
+		assert!(!Hext::is_constant_name("A-B"));
+	}
+
+	#[test]
+	fn conditional_in_macro() {
+		let test = "~big-endian msb0
+!macro field(wide, value)
+!if($wide) u16=$value !else u8=$value !end
+!end
+!field(0, 1) !field(1, 2)";
+		let cmp = vec![0x01, 0x00, 0x02];
+
+		assert_eq!(Hext::new().parse(test).unwrap(), cmp);
+	}
+
+	#[test]
+	fn ftest_conditional_error_location() {
+		let test = "~big-endian msb0\n!if(1)\n\t01 G\n!end";
+		let error = Hext::new().parse(test).unwrap_err();
+
+		assert_eq!(error.kind(), &ErrorKind::InvalidCharacter('G'));
+		assert_eq!((error.span().line, error.span().column), (3, 5));
+	}
+
+	#[test]
+	fn ftest_conditional_unclosed() {
+		for test in ["!if(1) 01", "!if(1) 01 !else 02", "!if(1) !if(0) !end"] {
+			let test = format!("~big-endian msb0\n{}", test);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::UnclosedIf
+			);
 		}
 	}
 
-	fn escape(c: char) -> Option<char> {
-		match c {
-			'\"' => Some('\"'),
-			'\\' => Some('\\'),
-			'n' => Some('\n'),
-			'r' => Some('\r'),
-			't' => Some('\t'),
-			_ => None,
+	#[test]
+	fn ftest_conditional_unmatched_else() {
+		for test in ["01 !else", "!if(1) 01 !else 02 !else 03 !end"] {
+			let test = format!("~big-endian msb0\n{}", test);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::UnmatchedElse
+			);
 		}
 	}
 
-	fn skip_nondata(mut chars: &mut Peekable<Chars>) {
-		loop {
-			match chars.peek() {
-				Some('#') => Self::skip_line(&mut chars),
-				Some(c) if c.is_whitespace() => {
-					chars.next();
+	#[test]
+	fn ftest_conditional_invalid() {
+		for condition in ["", "1 +", "=="] {
+			let test = format!("~big-endian msb0\n!if({}) 01 !end", condition);
+
+			assert_eq!(
+				Hext::new().parse(test).unwrap_err().kind(),
+				&ErrorKind::InvalidArguments {
+					directive: "if".into(),
+					arguments: condition.into()
 				}
-				_ => return,
-			};
+			);
 		}
-	}
 
-	fn skip_line(chars: &mut Peekable<Chars>) {
-		chars.find(|&c| c == '\n');
+		let test = "~big-endian msb0\n!if(FLAG) 01 !end";
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UndefinedConstant("FLAG".into())
+		);
 	}
 
-	fn consume_line(chars: &mut Peekable<Chars>) -> String {
-		chars.take_while(|&c| c != '\n').collect()
-	}
+	#[test]
+	fn ftest_conditional_block_left_open() {
+		let test = "~big-endian msb0\n!if(1) u8{ !end 00 }";
 
-	fn consume_until_whitespace(chars: &mut Peekable<Chars>) -> String {
-		chars.take_while(|&c| !c.is_whitespace()).collect()
+		assert_eq!(
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::UnclosedBlock
+		);
 	}
 
-	fn signed_le_bytes<S: AsRef<str>>(bitness: S, value: S) -> Result<Vec<u8>, Error> {
-		match bitness.as_ref() {
-			"8" => Ok(i8::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidSignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			"16" => Ok(i16::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidSignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			"32" => Ok(i32::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidSignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			"64" => Ok(i64::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidSignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			_ => return Err(Error::InvalidBitness(bitness.as_ref().to_string())),
-		}
+	//## Decompile Tests ##
+	#[test]
+	fn decompile_rows() {
+		let bytes = b"\x00\x01GIF89a\xFF\"quoted\\\"\n\x7F";
+		let cmp = "~big-endian msb0
+00 01 \"GIF89a\" FF \"\\\"qu\"  # 0x0000
+\"oted\\\\\\\"\\n\" 7F           # 0x000C
+";
+
+		let hext = Decompiler::new().width(12).decompile(bytes);
+		assert_eq!(hext, cmp);
+		assert_eq!(Hext::new().parse(&hext).unwrap(), bytes);
 	}
 
-	fn signed_smallest_le_bytes<S: AsRef<str>>(string: S) -> Result<Vec<u8>, ParseIntError> {
-		let large: i64 = i64::from_str_radix(string.as_ref(), 10)?;
+	#[test]
+	fn decompile_short_text_is_hex() {
+		let hext = Decompiler::new().offsets(false).decompile(b"abc\x00abcd");
 
-		Ok(if large > i32::MAX as i64 || large < i32::MIN as i64 {
-			large.to_le_bytes().to_vec()
-		} else if large > i16::MAX as i64 || large < i16::MIN as i64 {
-			(large as i32).to_le_bytes().to_vec()
-		} else if large > i8::MAX as i64 || large < i8::MIN as i64 {
-			(large as i16).to_le_bytes().to_vec()
-		} else {
-			(large as i8).to_le_bytes().to_vec()
-		})
+		assert_eq!(hext, "~big-endian msb0\n61 62 63 00 \"abcd\"\n");
 	}
 
-	fn unsigned_le_bytes<S: AsRef<str>>(bitness: S, value: S) -> Result<Vec<u8>, Error> {
-		match bitness.as_ref() {
-			"8" => Ok(u8::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidUnsignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			"16" => Ok(u16::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidUnsignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			"32" => Ok(u32::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidUnsignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			"64" => Ok(u64::from_str_radix(value.as_ref(), 10)
-				.map_err(|_| Error::InvalidUnsignedDecimal(value.as_ref().to_string()))?
-				.to_le_bytes()
-				.to_vec()),
-			_ => return Err(Error::InvalidBitness(bitness.as_ref().to_string())),
-		}
-	}
-
-	fn unsigned_smallest_le_bytes<S: AsRef<str>>(string: S) -> Result<Vec<u8>, ParseIntError> {
-		let large: u64 = u64::from_str_radix(string.as_ref(), 10)?;
-
-		Ok(if large > u32::MAX as u64 {
-			large.to_le_bytes().to_vec()
-		} else if large > u16::MAX as u64 {
-			(large as u32).to_le_bytes().to_vec()
-		} else if large > u8::MAX as u64 {
-			(large as u16).to_le_bytes().to_vec()
-		} else {
-			(large as u8).to_le_bytes().to_vec()
-		})
+	#[test]
+	fn decompile_no_strings() {
+		let hext = Decompiler::new()
+			.strings(0)
+			.offsets(false)
+			.decompile(b"text");
+
+		assert_eq!(hext, "~big-endian msb0\n74 65 78 74\n");
 	}
-}
 
-enum State {
-	ReadingHex,
-	ReadingUnsizedDecimal,
-	ReadingSignedDecimal,
-	ReadingUnsignedDecimal,
-	ReadingBinary,
-	ReadingLiteral,
-}
+	#[test]
+	fn decompile_empty() {
+		let hext = Decompiler::new().decompile(&[]);
 
-#[cfg(test)]
-mod test {
-	use super::*;
+		assert_eq!(hext, "~big-endian msb0\n");
+		assert_eq!(Hext::new().parse(&hext).unwrap(), vec![]);
+	}
 
 	#[test]
-	fn pares_header_success() {
-		// Recognizes the keytwords...
-		assert_eq!(
-			Hext::parse_header("msb0 big-endian").unwrap(),
-			Header {
-				byteorder: ByteOrder::BigEndian,
-				bitorder: crate::BitOrder::Msb0,
-				negativekind: NegativeKind::TwosCompliment,
-				pad_bits: false
-			}
-		);
+	fn decompile_round_trip() {
+		// A little LCG so the test doesn't need a random number crate. Bytes
+		// are skewed towards text so there are plenty of runs of it.
+		let mut state = 0x2545F4914F6CDD1Du64;
+		let text = b" \t\n\r\"\\#!@$%{}azAZ09~";
+		let mut next = || {
+			state = state
+				.wrapping_mul(6364136223846793005)
+				.wrapping_add(1442695040888963407);
+			(state >> 33) as u32
+		};
 
-		assert_eq!(
-			Hext::parse_header("lsb0 little-endian").unwrap(),
-			Header {
-				byteorder: ByteOrder::LittleEndian,
-				bitorder: crate::BitOrder::Lsb0,
-				negativekind: NegativeKind::TwosCompliment,
-				pad_bits: false
-			}
-		);
+		for length in 0..300 {
+			let bytes: Vec<u8> = (0..length)
+				.map(|_| match next() % 4 {
+					0 => next() as u8,
+					_ => text[next() as usize % text.len()],
+				})
+				.collect();
 
-		// ...In either order
-		assert_eq!(
-			Hext::parse_header("big-endian lsb0").unwrap(),
-			Header {
-				byteorder: ByteOrder::BigEndian,
-				bitorder: crate::BitOrder::Lsb0,
-				negativekind: NegativeKind::TwosCompliment,
-				pad_bits: false
+			for width in [1, 7, 16] {
+				let hext = Decompiler::new().width(width).decompile(&bytes);
+				assert_eq!(Hext::new().parse(&hext).unwrap(), bytes, "{}", hext);
 			}
-		);
+		}
+	}
+
+	//## Schema Tests ##
+	/// A packet with a UDP header, made with hext so the checksum is right
+	const UDP_PACKET: &str = "~big-endian msb0
+@ip{ 45 00 u16=33 u16=1 40 00 u8=64 u8=17 !internet(@ip) =10 =0 =0 =1 =10 =0 =0 =2 }
+u16=1234 u16=53 u16=13 u16=0 \"hello\"";
+
+	/// Each format but GIF in a file small enough to write out
+	fn schema_samples() -> Vec<(Format, Vec<u8>)> {
+		let sources = [
+			(
+				Format::Png,
+				"~big-endian msb0
+				89 \"PNG\" 0D 0A 1A 0A u32=13 @h{ \"IHDR\" u32=1 u32=1 08 00 00 00 00 } !crc32(@h)
+				u32=2 @d{ \"IDAT\" 78 9C } !crc32(@d) u32=0 @e{ \"IEND\" } !crc32(@e)",
+			),
+			(
+				Format::Bmp,
+				"~little-endian msb0
+				\"BM\" u32=62 00 00 00 00 u32=54 u32=40 i32=1 i32=-1 u16=1 u16=24
+				u32=0 u32=8 i32=2835 i32=2835 u32=0 u32=0 FF 00 00 00 00 00 00 00",
+			),
+			(
+				Format::Wav,
+				"~little-endian msb0
+				\"RIFF\" u32=40 \"WAVE\" \"fmt \" u32=16 u16=1 u16=1 u32=8000
+				u32=8000 u16=1 u16=8 \"data\" u32=3 80 81 82 00",
+			),
+			(
+				Format::Elf,
+				"~big-endian msb0
+				7F \"ELF\" 01 02 01 00 00 00 00 00 00 00 00 00 u16=2 u16=8 u32=1
+				u32=0x400000 u32=52 u32=0 u32=0 u16=52 u16=32 u16=0 u16=40 u16=0 u16=0 00",
+			),
+			(Format::Ipv4, UDP_PACKET),
+		];
+
+		let mut samples = vec![(Format::Gif, include_bytes!("../../test.gif").to_vec())];
+		for (format, source) in sources {
+			samples.push((format, Hext::new().parse(source).unwrap()));
+		}
+		samples
 	}
 
 	#[test]
-	fn parse_header_fail_twobits() {
-		assert_eq!(
-			Hext::parse_header("lsb0 msb0"),
-			Err(InvalidHeaderKind::TwoBitOrder.into())
-		)
+	fn schema_detect() {
+		for (format, bytes) in schema_samples() {
+			assert_eq!(Format::detect(&bytes), Some(format));
+			assert_eq!(Format::from_name(format.name()), Some(format));
+		}
+
+		assert_eq!(Format::detect(b"GIF90a"), None);
+		assert_eq!(Format::from_name("jpeg"), None);
 	}
 
 	#[test]
-	fn parse_header_fail_twobytes() {
-		assert_eq!(
-			Hext::parse_header("little-endian big-endian"),
-			Err(InvalidHeaderKind::TwoByteOrder.into())
-		)
+	fn schema_ipv4() {
+		let bytes = Hext::new().parse(UDP_PACKET).unwrap();
+		let cmp = "~big-endian msb0
+# IPv4 header
+@ip{
+	u4=4           # Version
+	u4=5           # Header length in 32-bit words
+	u6=0           # DSCP
+	u2=0           # ECN
+	u16=33         # Total length
+	u16=1          # Identification
+	.0             # Reserved
+	.1             # Don't fragment
+	.0             # More fragments
+	u13=0          # Fragment offset
+	u8=64          # Time to live
+	u8=17          # Protocol, which is 6 for TCP and 17 for UDP
+	!internet(@ip) # Header checksum
+	=10 =0 =0 =1   # Source address
+	=10 =0 =0 =2   # Destination address
+}
+
+# UDP header
+u16=1234 # Source port
+u16=53   # Destination port
+u16=13   # Length
+u16=0    # Checksum
+
+# Payload
+\"hello\"  # 0x001C
+";
+
+		assert_eq!(Decompiler::new().decompile(&bytes), cmp);
 	}
 
 	#[test]
-	fn parse_header_fail_nobits() {
-		assert_eq!(
-			Hext::parse_header("big-endian"),
-			Err(InvalidHeaderKind::NoBitOrder.into())
-		)
+	fn schema_gif() {
+		let bytes = include_bytes!("../../test.gif");
+		let hext = Decompiler::new().decompile(bytes);
+
+		assert!(hext.starts_with("~little-endian msb0\n\"GIF89a\" # Signature and version\n"));
+		assert!(hext.contains("\n# Graphic Control Extension\n\"!\" # Extension introducer\n"));
+		assert!(hext.contains("\n=128 =0 =255 # Color 1\n"));
+		assert!(hext.ends_with("\n# Trailer\n\";\" # End of the GIF\n"));
 	}
 
 	#[test]
-	fn parse_header_fail_nobytes() {
-		assert_eq!(
-			Hext::parse_header("msb0"),
-			Err(InvalidHeaderKind::NoByteOrder.into())
-		)
+	fn schema_png_bad_crc() {
+		let bytes = b"\x89PNG\r\n\x1A\n\x00\x00\x00\x00IEND\x00\x00\x00\x00";
+		let hext = Decompiler::new().decompile(bytes);
+
+		assert!(hext.contains("\n\"IEND\" # Chunk type\nu32=0  # CRC, which doesn't match\n"));
+		assert_eq!(Hext::new().parse(&hext).unwrap(), bytes);
 	}
 
 	#[test]
-	fn parse_header_fail_invalidproperty() {
-		assert_eq!(
-			Hext::parse_header("lsb0 big-endian invalidproperty"),
-			Err(InvalidHeaderKind::InvalidProperty("invalidproperty".into()).into())
-		)
+	fn schema_big_endian_elf() {
+		let (_, bytes) = schema_samples().remove(4);
+		let hext = Decompiler::new().decompile(&bytes);
+
+		assert!(hext.starts_with("~big-endian msb0\n"));
+		assert!(hext.contains("\nu32=4194304 # Entry point\n"));
 	}
 
-	//## Bytes tests ##
 	#[test]
-	fn test_onebyte() {
-		let test = "~little-endian msb0\n41";
-		let cmp = vec![0x41];
+	fn schema_raw() {
+		let hext = Decompiler::new().detect(false).decompile(b"GIF89a");
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(hext, "~big-endian msb0\n\"GIF89a\"  # 0x0000\n");
 	}
 
 	#[test]
-	fn test_only_comment() {
-		let test = "~little-endian msb0\n# Comment";
+	fn schema_round_trip() {
+		// Cut short, with junk after, or taken for the wrong format, every
+		// layout has to give back the bytes
+		for (_, bytes) in schema_samples() {
+			for length in 0..=bytes.len() {
+				let mut bytes = bytes[..length].to_vec();
+				for junk in [vec![], vec![0x3B, 0xFF, 0x00]] {
+					bytes.extend(junk);
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), vec![]);
+					// Not decompile, which would hide a mistake behind plain rows
+					for format in Format::ALL {
+						let layout = format.describe(&bytes);
+						let hext = Decompiler::new().render_layout(&layout, &bytes);
+						assert_eq!(Hext::new().parse(&hext).unwrap(), bytes, "{}", hext);
+					}
+				}
+			}
+		}
 	}
 
+	//## Lexer and AST Tests ##
 	#[test]
-	fn test_1byte_comment() {
-		let test = "~little-endian msb0\n41 #A";
-		let cmp = vec![0x41];
+	fn lexer_tokens() {
+		let test = "~big-endian msb0\nDE .01 u16be=4 \"a\\n\" @x{ } # done";
+		let kinds: Vec<TokenKind> = Lexer::new(test).map(|token| token.unwrap().kind).collect();
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(
+			kinds,
+			vec![
+				TokenKind::Header("big-endian msb0".into()),
+				TokenKind::Octet(0xDE),
+				TokenKind::Bits(vec![false, true]),
+				TokenKind::Field(FieldKind::Unsigned, "16be=4".into()),
+				TokenKind::Literal(b"a\n".to_vec()),
+				TokenKind::NamedBlock("x".into()),
+				TokenKind::Close,
+				TokenKind::Comment(" done".into()),
+			]
+		);
 	}
 
 	#[test]
-	fn test_byte_nospace_comment() {
-		let test = "~little-endian msb0\n41#A";
-		let cmp = vec![0x41];
+	fn lexer_spans() {
+		let test = "~big-endian msb0\n$A = 4 # four\nf32=1.5";
+		let spans: Vec<&str> = Lexer::new(test)
+			.map(|token| &test[token.unwrap().span.range()])
+			.collect();
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(
+			spans,
+			vec!["~big-endian msb0", "$A = 4 ", "# four", "f32=1.5"]
+		);
 	}
 
 	#[test]
-	fn test_2byte_multiline() {
-		let test = "~little-endian msb0\n41\n42";
-		let cmp = vec![0x41, 0x42];
+	fn lexer_comments_in_bits() {
+		let test = "~big-endian msb0\n.0100# High\n0001 # Low\n01";
+		let tokens: Vec<Token> = Lexer::new(test).map(Result::unwrap).collect();
+		let kinds: Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(
+			kinds,
+			vec![
+				&TokenKind::Header("big-endian msb0".into()),
+				&TokenKind::Bits(vec![false, true, false, false, false, false, false, true]),
+				&TokenKind::Comment(" High".into()),
+				&TokenKind::Comment(" Low".into()),
+				&TokenKind::Octet(0x01),
+			]
+		);
+		assert_eq!(&test[tokens[2].span.range()], "# High");
+		assert_eq!(tokens[3].span.line, 3);
+
+		let nodes = Ast::parse(test).unwrap().nodes;
+		assert!(matches!(&nodes[2].kind, NodeKind::Comment(comment) if comment == " High"));
+		assert_eq!(Hext::new().parse(test).unwrap(), vec![0x41, 0x01]);
+		assert_eq!(
+			Formatter::new().format(test).unwrap(),
+			format!("{}\n", test)
+		);
 	}
 
 	#[test]
-	fn test_2bytes_nospace() {
-		let test = "~little-endian msb0\n4142";
-		let cmp = vec![0x41, 0x42];
+	fn lexer_bodies_are_text() {
+		let test = "!if(X) 01 !else not hext !end";
+		let token = Lexer::new(test).next().unwrap().unwrap();
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(&test[token.span.range()], "!if(X)");
+		match token.kind {
+			TokenKind::If {
+				condition,
+				then,
+				otherwise,
+			} => {
+				assert_eq!(condition.as_deref(), Some("X"));
+				assert_eq!(then.text, " 01 ");
+				assert_eq!(&test[otherwise.unwrap().span.range()], " not hext ");
+			}
+			kind => panic!("{:?}", kind),
+		}
 	}
 
-	//## Bit Tests ##
 	#[test]
-	fn test_8bits() {
-		let test = "~little-endian msb0\n.01000001";
-		let cmp = vec![0x41];
+	fn lexer_stops_at_error() {
+		let mut lexer = Lexer::new("01 G 02");
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(lexer.next().unwrap().unwrap().kind, TokenKind::Octet(1));
+		assert_eq!(
+			lexer.next().unwrap().unwrap_err().kind(),
+			&ErrorKind::InvalidCharacter('G')
+		);
+		assert!(lexer.next().is_none());
 	}
 
 	#[test]
-	fn test_8bits_hex10() {
-		let test = "~little-endian msb0\n.01000001 10";
-		let cmp = vec![0x41, 0x10];
+	fn ast_structure() {
+		let test = "# a file\n~little-endian msb0\nDEAD BE u8{ 01 } * 2";
+		let ast = Ast::parse(test).unwrap();
+		let kinds: Vec<&NodeKind> = ast.nodes.iter().map(|node| &node.kind).collect();
+
+		assert!(matches!(
+			kinds[..],
+			[
+				NodeKind::Comment(_),
+				NodeKind::Header(Header {
+					byteorder: ByteOrder::LittleEndian,
+					..
+				}),
+				NodeKind::Octets(_),
+				NodeKind::Octets(_),
+				NodeKind::Block {
+					opener: Opener::Length(_),
+					..
+				},
+				NodeKind::Repeat(_),
+			]
+		));
+		assert_eq!(kinds[2], &NodeKind::Octets(vec![0xDE, 0xAD]));
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		let NodeKind::Block { nodes, close, .. } = kinds[4] else {
+			unreachable!()
+		};
+		assert_eq!(nodes[0].kind, NodeKind::Octets(vec![1]));
+		assert_eq!(&test[close.range()], "}");
 	}
 
 	#[test]
-	fn test_8bit_comment() {
-		let test = "~little-endian msb0\n.01000001 # A";
-		let cmp = vec![0x41];
+	fn ast_lower() {
+		let test = "~big-endian msb0\n$N = 2\n@data{ u8{ \"hi\" } * N } u16=@data.end";
+		let ast = Ast::parse(test).unwrap();
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp)
+		assert_eq!(
+			Hext::new().lower(&ast).unwrap(),
+			Hext::new().parse(test).unwrap()
+		);
 	}
 
 	#[test]
-	fn test_8bit_nospace_comment() {
-		let test = "~little-endian msb0\n.01000001#A";
-		let cmp = vec![0x41];
+	fn ast_lower_by_hand() {
+		let node = |kind| Node {
+			kind,
+			span: Span::default(),
+		};
+		let ast = Ast {
+			nodes: vec![
+				node(NodeKind::Header(Header {
+					bitorder: BitOrder::Msb0,
+					byteorder: ByteOrder::BigEndian,
+					negativekind: NegativeKind::TwosCompliment,
+					pad_bits: false,
+				})),
+				node(NodeKind::Field {
+					kind: FieldKind::Signed,
+					width: "16".into(),
+					byteorder: None,
+					value: "-2".into(),
+				}),
+				node(NodeKind::Repeat("2".into())),
+			],
+		};
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(
+			Hext::new().lower(&ast).unwrap(),
+			vec![0xFF, 0xFE, 0xFF, 0xFE]
+		);
 	}
 
 	#[test]
-	fn test_1bit() {
-		let test = "~little-endian msb0 padbits\n.1";
-		let cmp = vec![0x01];
+	fn ftest_ast() {
+		for (test, kind) in [
+			("41", ErrorKind::NoHeader),
+			("~big-endian msb0\n{ 01", ErrorKind::UnclosedBlock),
+			("~big-endian msb0\n01 }", ErrorKind::UnmatchedBlockClose),
+			(
+				"~big-endian msb0\nu16",
+				ErrorKind::InvalidDecimal("16".into()),
+			),
+		] {
+			assert_eq!(Ast::parse(test).unwrap_err().kind(), &kind);
+		}
+	}
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+	//## Format Tests ##
+	#[test]
+	fn format_layout() {
+		let test = "# Start\n~padbits msb0  big-endian\n$A=4   # Four\n  deadbeef   u16=A # Some\n\"hi\"  # More\n\n\n@data{\n        {  01 02 }   # Inside\n      03\n}";
+		let cmp = "# Start\n~big-endian msb0 padbits\n$A = 4         # Four\nDEADBEEF u16=A # Some\n\"hi\"           # More\n\n@data{\n\t{ 01 02 } # Inside\n\t03\n}\n";
+
+		assert_eq!(Formatter::new().format(test).unwrap(), cmp);
 	}
 
 	#[test]
-	fn test_8bits_halved_space() {
-		let test_space = "~little-endian msb0\n.0100 .0010";
-		let cmp = vec![0x42];
+	fn format_group() {
+		let test = "~big-endian msb0\n01 0203 040506\nAABB CC DDEE * 2";
+		let cmp = "~big-endian msb0\n0102 0304 0506\nAABB CC DDEE * 2\n";
+
+		assert_eq!(Formatter::new().group(2).format(test).unwrap(), cmp);
 
-		assert_eq!(Hext::new().parse(&test_space).unwrap(), cmp);
+		// Only the group that's repeated has to stay together
+		let cmp = "~big-endian msb0\n01 02 03 04 05 06\nAA BB CC DDEE * 2\n";
+		assert_eq!(Formatter::new().group(1).format(test).unwrap(), cmp);
 	}
 
 	#[test]
-	fn test_8bits_halved_line() {
-		let test_line = "~little-endian msb0\n.0100\n.0010";
-		let cmp = vec![0x42];
+	fn format_bodies_kept() {
+		let test = "~big-endian msb0\n!macro two(a)\n    $a $a\n!end\n  !if(X) 01\n   02 !else 03 !end   04";
+		let cmp =
+			"~big-endian msb0\n!macro two(a)\n    $a $a\n!end\n!if(X) 01\n   02 !else 03 !end 04\n";
 
-		assert_eq!(Hext::new().parse(&test_line).unwrap(), cmp);
+		assert_eq!(Formatter::new().format(test).unwrap(), cmp);
 	}
 
 	#[test]
-	fn test_8bits_halved_line_comments() {
-		let test_line_comments = "~little-endian msb0\n.0100#Half of capital letter\n.0010 # B";
-		let cmp = vec![0x42];
+	fn format_without_header() {
+		let test = "  01 # Included\n~little-endian\nu16=1";
+		let cmp = "01 # Included\n~little-endian\nu16=1\n";
 
-		assert_eq!(Hext::new().parse(&test_line_comments).unwrap(), cmp);
+		assert_eq!(Formatter::new().format(test).unwrap(), cmp);
 	}
 
 	#[test]
-	fn test_1bit_then_byte() {
-		let test = "~little-endian msb0 padbits\n.1 41";
-		let cmp = vec![0x01, 0x41];
+	fn format_same_bytes() {
+		let test = include_str!("../../gif.hxt");
+		let cmp = Hext::new().parse(test).unwrap();
+
+		for formatter in [
+			Formatter::new(),
+			Formatter::new().group(1),
+			Formatter::new().group(3),
+		] {
+			let formatted = formatter.format(test).unwrap();
+			assert_eq!(Hext::new().parse(&formatted).unwrap(), cmp);
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+			// Formatting twice changes nothing more
+			assert_eq!(formatter.format(&formatted).unwrap(), formatted);
+		}
 	}
 
-	//## Literal Tests ##
 	#[test]
-	fn literal_multibyte() {
-		let test = "~big-endian lsb0\n\"🥺\"";
-		let cmp = vec![0xf0, 0x9f, 0xa5, 0xba];
+	fn ftest_format() {
+		for (test, kind) in [
+			("~big-endian msb0\n{ 01", ErrorKind::UnclosedBlock),
+			("~big-endian msb0\n0", ErrorKind::IncompleteOctet),
+			(
+				"~big-endian msb0\n~msb0",
+				ErrorKind::InvalidHeader(InvalidHeaderKind::HeaderOnly("msb0".into())),
+			),
+		] {
+			assert_eq!(Formatter::new().format(test).unwrap_err().kind(), &kind);
+		}
+	}
+
+	//## Source Map Tests ##
+	/// The byte ranges of a source map with the file, line, and column of each
+	fn map_parse(
+		test: &str,
+		files: &'static [(&'static str, &'static str)],
+	) -> Vec<(Range<usize>, Option<String>, usize, usize)> {
+		let (_, map) = Hext::new()
+			.name("main.hxt")
+			.resolver(ListResolver(files))
+			.parse_mapped(test)
+			.unwrap();
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		map.mappings
+			.into_iter()
+			.map(|mapping| {
+				(
+					mapping.bytes,
+					mapping.file,
+					mapping.span.line,
+					mapping.span.column,
+				)
+			})
+			.collect()
 	}
 
-	//## Decimal Tests ##
 	#[test]
-	fn decimal_unsized_u8() {
-		let test = "~big-endian lsb0\n=200";
-		let cmp = vec![200];
+	fn sourcemap_lines() {
+		let test = "~big-endian msb0\n01 02 # Two\n\"hi\"\nu16=4 .1111 .0000";
+		let main = || Some("main.hxt".to_string());
+		let cmp = vec![
+			(0..1, main(), 2, 1),
+			(1..2, main(), 2, 4),
+			(2..4, main(), 3, 1),
+			(4..6, main(), 4, 1),
+			(6..7, main(), 4, 7),
+			(6..7, main(), 4, 13),
+		];
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(map_parse(test, &[]), cmp);
 	}
 
 	#[test]
-	fn decimal_unsized_i8() {
-		let test = "~big-endian lsb0\n=-127";
-		let cmp = (-127i8).to_be_bytes().to_vec();
+	fn sourcemap_same_bytes() {
+		let test = include_str!("../../gif.hxt");
+		let (bytes, map) = Hext::new().parse_mapped(test).unwrap();
+		assert_eq!(bytes, Hext::new().parse(test).unwrap());
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		// The length of the graphic control extension's block
+		let mapping = map.find(0x2D).unwrap();
+		assert_eq!((mapping.span.line, mapping.span.column), (36, 1));
+		assert_eq!(mapping.file, None);
+
+		assert_eq!(map.find(bytes.len()), None);
 	}
 
 	#[test]
-	fn decimal_unsized_u32() {
-		let test = "~little-endian lsb0\n=65536";
-		let cmp = 65536u32.to_le_bytes().to_vec();
+	fn sourcemap_padding() {
+		// The padding goes in front of the bits, so the octets after them
+		// start on the next octet
+		let test = "~big-endian msb0 padbits\n.101\n02";
+		let main = || Some("main.hxt".to_string());
+		let cmp = vec![(0..1, main(), 2, 1), (1..2, main(), 3, 1)];
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(map_parse(test, &[]), cmp);
 	}
 
 	#[test]
-	fn decimal_unsized_i32() {
-		let test = "~little-endian lsb0\n=-40000";
-		let cmp = (-40000i32).to_le_bytes().to_vec();
+	fn sourcemap_blocks() {
+		let test = "~big-endian msb0\nu16{ 01 }\nvarint{\n\t02 03\n}";
+		let main = || Some("main.hxt".to_string());
+		let cmp = vec![
+			(0..2, main(), 2, 1),
+			(2..3, main(), 2, 6),
+			(3..4, main(), 3, 1),
+			(4..5, main(), 4, 2),
+			(5..6, main(), 4, 5),
+		];
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(map_parse(test, &[]), cmp);
 	}
 
 	#[test]
-	fn decimal_sized_u16() {
-		let test = "~little-endian lsb0\nu16=65534";
-		let cmp = 65534u16.to_le_bytes().to_vec();
+	fn sourcemap_repeat() {
+		let test = "~big-endian msb0\nAABB * 2";
+		let main = || Some("main.hxt".to_string());
+		let cmp = vec![(0..2, main(), 2, 1), (2..4, main(), 2, 1)];
 
-		assert_eq!(Hext::new().parse(&test).unwrap(), cmp);
+		assert_eq!(map_parse(test, &[]), cmp);
 	}
 
 	#[test]
-	fn decimal_overflow_sized_u16() {
-		let test = "~little-endian lsb0\nu16=65536";
+	fn sourcemap_included_and_expanded() {
+		let test =
+			"~big-endian msb0\n!macro two(a)\n\t$a $a\n!end\n!include(\"mid.hxt\")\n!two(05)";
+		let cmp = vec![
+			(0..1, Some("mid.hxt".to_string()), 1, 1),
+			(1..2, Some("mid.hxt".to_string()), 2, 3),
+			(2..3, Some("main.hxt".to_string()), 3, 2),
+			(3..4, Some("main.hxt".to_string()), 3, 5),
+		];
 
-		assert_eq!(
-			Hext::new().parse(&test).unwrap_err(),
-			Error::InvalidUnsignedDecimal("65536".into())
-		);
+		assert_eq!(map_parse(test, &[("mid.hxt", "01\n  02")]), cmp);
+	}
+
+	#[test]
+	fn sourcemap_json() {
+		let map = SourceMap {
+			mappings: vec![Mapping {
+				bytes: 0..2,
+				file: Some("a \"b\".hxt".into()),
+				span: Span {
+					line: 3,
+					column: 4,
+					..Span::default()
+				},
+			}],
+		};
+		let cmp = "{\n\t\"mappings\": [\n\t\t{\"start\": 0, \"end\": 2, \"file\": \"a \\\"b\\\".hxt\", \"line\": 3, \"column\": 4}\n\t]\n}\n";
+
+		assert_eq!(map.to_json(), cmp);
+		assert_eq!(SourceMap::default().to_json(), "{\n\t\"mappings\": []\n}\n");
 	}
 
 	//## Everything ##
@@ -663,7 +5054,7 @@ mod test {
 			.unwrap()
 			.into_bytes();
 
-		assert_eq!(Hext::new().parse(&to_parse).unwrap(), cmp)
+		assert_eq!(Hext::new().parse(to_parse).unwrap(), cmp)
 	}
 
 	//## Failing Tests ##
@@ -672,8 +5063,8 @@ mod test {
 		let test = "~little-endian msb0\n4";
 
 		assert_eq!(
-			Hext::new().parse(&test).unwrap_err(),
-			Error::IncompleteOctet
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::IncompleteOctet
 		);
 	}
 
@@ -682,24 +5073,88 @@ mod test {
 		let test = "~little-endian msb0\nG";
 
 		assert_eq!(
-			Hext::new().parse(&test).unwrap_err(),
-			Error::InvalidCharacter('G')
+			Hext::new().parse(test).unwrap_err().kind(),
+			&ErrorKind::InvalidCharacter('G')
 		);
 	}
 
 	#[test]
 	fn ftest_unaligned_bit() {
 		let test = "~little-endian msb0\n.1";
-		let cmp = Error::UnalignedBits;
+		let cmp = ErrorKind::UnalignedBits;
 
-		assert_eq!(Hext::new().parse(&test).unwrap_err(), cmp);
+		assert_eq!(Hext::new().parse(test).unwrap_err().kind(), &cmp);
 	}
 
 	#[test]
 	fn ftest_unaligned_bit_then_byte() {
 		let test = "~little-endian msb0\n.1 41";
-		let cmp = Error::UnalignedBits;
+		let cmp = ErrorKind::UnalignedBits;
+
+		assert_eq!(Hext::new().parse(test).unwrap_err().kind(), &cmp);
+	}
+
+	//## Span Tests ##
+	fn error_span(test: &str) -> Span {
+		Hext::new().parse(test).unwrap_err().span()
+	}
+
+	#[test]
+	fn span_invalidcharacter() {
+		let test = "~little-endian msb0\n41 42\n  G";
+
+		assert_eq!(
+			error_span(test),
+			Span {
+				line: 3,
+				column: 3,
+				start: 28,
+				end: 29,
+				source: 0
+			}
+		);
+	}
+
+	#[test]
+	fn span_invalid_decimal_token() {
+		let test = "~little-endian msb0\n41 u16=65536 42";
+		let span = error_span(test);
+
+		assert_eq!(&test[span.range()], "u16=65536");
+		assert_eq!((span.line, span.column), (2, 4));
+	}
+
+	#[test]
+	fn span_columns_count_characters() {
+		let test = "~little-endian msb0\n\"🥺\" 4";
+		let span = error_span(test);
+
+		assert_eq!(&test[span.range()], "4");
+		assert_eq!((span.line, span.column), (2, 5));
+	}
+
+	#[test]
+	fn span_unaligned_bits_covers_runs() {
+		let test = "~little-endian msb0\n.01 .1 41";
+		let span = error_span(test);
+
+		assert_eq!(&test[span.range()], ".01 .1");
+	}
+
+	#[test]
+	fn span_header_property() {
+		let test = "~little-endian msb0 paddbits\n41";
+		let span = error_span(test);
+
+		assert_eq!(&test[span.range()], "paddbits");
+		assert_eq!((span.line, span.column), (1, 21));
+	}
+
+	#[test]
+	fn span_unclosed_literal() {
+		let test = "~little-endian msb0\n\"abc\n41";
+		let span = error_span(test);
 
-		assert_eq!(Hext::new().parse(&test).unwrap_err(), cmp);
+		assert_eq!(&test[span.range()], "\"abc");
 	}
 }
@@ -0,0 +1,347 @@
+use crate::expr::Expression;
+use crate::Span;
+use std::ops::Range;
+use std::rc::Rc;
+
+/// A macro made with `!macro name(a, b)`, whose body is everything up to the
+/// `!end` that closes it. Invoking it as `!name(1, "two")` parses the body
+/// with `$a` and `$b` replaced by the arguments as they were written.
+#[derive(Debug)]
+pub(crate) struct Macro {
+	pub name: String,
+	pub parameters: Vec<String>,
+	pub body: String,
+	/// Where the body is in the source that defined it
+	span: Span,
+}
+
+/// Where a piece of an expansion came from
+#[derive(Debug)]
+enum Piece {
+	/// The body, starting at this offset into it
+	Body(usize),
+	/// The argument for the parameter written at this range of the body
+	Argument(Range<usize>),
+}
+
+/// The text of one invocation, with enough to find where any part of it
+/// came from in the macro's body
+#[derive(Debug)]
+pub(crate) struct Expansion {
+	pub definition: Rc<Macro>,
+	/// Each piece with the offset into the expansion it starts at
+	pieces: Vec<(usize, Piece)>,
+}
+
+impl Macro {
+	/// Make a macro from what follows `!macro` on its line, as in
+	/// `tlv(type, value)`. The parentheses can be left off if there are no
+	/// parameters. None if the name or parameters aren't valid. The body,
+	/// which starts at `span`, is filled in once its end is found.
+	pub fn parse(definition: &str, span: Span) -> Option<Self> {
+		let definition = definition.trim();
+		let (name, parameters) = match definition.split_once('(') {
+			Some((name, parameters)) => (name.trim_end(), Some(parameters.strip_suffix(')')?)),
+			None => (definition, None),
+		};
+
+		if name.is_empty() || !name.chars().all(is_name_character) {
+			return None;
+		}
+
+		let parameters: Vec<String> = split_arguments(parameters.unwrap_or_default())
+			.into_iter()
+			.map(str::to_string)
+			.collect();
+		for (index, parameter) in parameters.iter().enumerate() {
+			let valid = parameter.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+				&& parameter
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+			if !valid || parameters[..index].contains(parameter) {
+				return None;
+			}
+		}
+
+		Some(Self {
+			name: name.to_string(),
+			parameters,
+			body: String::new(),
+			span,
+		})
+	}
+
+	/// The source the macro was defined in
+	pub fn source(&self) -> usize {
+		self.span.source
+	}
+
+	/// Replace every `$parameter` in the body with its argument. Comments and
+	/// string literals are left alone. `arguments` must be one per parameter.
+	///
+	/// Where a parameter is part of an expression, an argument with an
+	/// operator in it is put in parentheses, so `u8=$x*2` with `1+2` is six
+	/// rather than five. Anywhere else it goes in as it was written.
+	pub fn expand(self: &Rc<Self>, arguments: &[&str]) -> (String, Expansion) {
+		let grouped: Vec<Option<String>> = arguments
+			.iter()
+			.map(|argument| match Expression::parse(argument, |_| None) {
+				Ok(Expression::Binary(..)) => Some(format!("({})", argument)),
+				_ => None,
+			})
+			.collect();
+
+		let mut text = String::with_capacity(self.body.len());
+		let mut pieces = vec![(0, Piece::Body(0))];
+
+		let mut scanner = Scanner::new(&self.body);
+		let mut copied = 0;
+		while let Some((index, c)) = scanner.next_code() {
+			if c != '$' {
+				continue;
+			}
+
+			let rest = &self.body[index + 1..];
+			let length = rest
+				.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+				.unwrap_or(rest.len());
+			let Some(parameter) = self.parameters.iter().position(|p| *p == rest[..length]) else {
+				continue;
+			};
+			let end = index + 1 + length;
+
+			text.push_str(&self.body[copied..index]);
+			pieces.push((text.len(), Piece::Argument(index..end)));
+			let line = self.body[..index]
+				.rfind('\n')
+				.map_or(0, |newline| newline + 1);
+			match &grouped[parameter] {
+				Some(grouped) if in_expression(&self.body[line..index]) => text.push_str(grouped),
+				_ => text.push_str(arguments[parameter]),
+			}
+			pieces.push((text.len(), Piece::Body(end)));
+
+			scanner.skip_to(end);
+			copied = end;
+		}
+		text.push_str(&self.body[copied..]);
+
+		let expansion = Expansion {
+			definition: self.clone(),
+			pieces,
+		};
+		(text, expansion)
+	}
+}
+
+impl Expansion {
+	/// Turn a span in the expansion into one in the source the macro was
+	/// defined in. Anything in an argument points at its parameter.
+	pub fn original(&self, span: Span) -> Span {
+		let definition = &self.definition;
+		let start = self.offset(span.start, false);
+		let end = self.offset(span.end, true).max(start);
+
+		let before = &definition.body[..start];
+		let (line, column) = match before.rfind('\n') {
+			Some(newline) => (
+				definition.span.line + before.matches('\n').count(),
+				before[newline + 1..].chars().count() + 1,
+			),
+			None => (
+				definition.span.line,
+				definition.span.column + before.chars().count(),
+			),
+		};
+
+		Span {
+			line,
+			column,
+			start: definition.span.start + start,
+			end: definition.span.start + end,
+			source: definition.span.source,
+		}
+	}
+
+	/// The offset into the body an offset into the expansion came from.
+	/// The end of a span belongs with what's before it.
+	fn offset(&self, offset: usize, end: bool) -> usize {
+		let index = self.pieces.partition_point(|(start, _)| match end {
+			true => *start < offset,
+			false => *start <= offset,
+		});
+
+		match &self.pieces[index.saturating_sub(1)] {
+			(start, Piece::Body(body)) => body + offset - start,
+			(_, Piece::Argument(range)) if end => range.end,
+			(_, Piece::Argument(range)) => range.start,
+		}
+	}
+}
+
+/// Whether `c` can be in the name of a directive or macro
+pub(crate) fn is_name_character(c: char) -> bool {
+	c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Whether something at the end of `line`, which is the start of a line up
+/// to it, is part of an expression. That's a constant's value, a field's value
+/// or length adjustment, a repeat count, or in parentheses other than the
+/// arguments of a macro or checksum.
+fn in_expression(line: &str) -> bool {
+	if line.trim_start().starts_with('$') && line.contains('=') {
+		return true;
+	}
+
+	// Whether each open parenthesis holds an expression, and where the token
+	// we're in started
+	let mut groups = vec![];
+	let mut token = 0;
+
+	let mut scanner = Scanner::new(line);
+	while let Some((index, c)) = scanner.next_code() {
+		match c {
+			'(' => {
+				let before = &line[..index];
+				let directive = before
+					.rfind('!')
+					.map(|bang| &before[bang + 1..])
+					.filter(|name| !name.is_empty() && name.chars().all(is_name_character));
+				groups.push(
+					!matches!(directive, Some(name) if !["if", "align", "pad", "embed"].contains(&name)),
+				);
+			}
+			')' => {
+				groups.pop();
+			}
+			c if c.is_whitespace() && groups.is_empty() => token = index + c.len_utf8(),
+			_ => (),
+		}
+	}
+
+	match groups.last() {
+		Some(expression) => *expression,
+		None => {
+			line[token..].contains(['=', '+', '-'])
+				|| line[token..].starts_with('*')
+				|| line[..token].trim_end().ends_with('*')
+		}
+	}
+}
+
+/// Split the arguments of an invocation on the commas that aren't in
+/// parentheses or string literals. Nothing at all is no arguments.
+pub(crate) fn split_arguments(arguments: &str) -> Vec<&str> {
+	if arguments.trim().is_empty() {
+		return vec![];
+	}
+
+	let mut split = vec![];
+	let mut depth = 0usize;
+	let mut start = 0;
+
+	let mut scanner = Scanner::new(arguments);
+	while let Some((index, c)) = scanner.next_code() {
+		match c {
+			'(' => depth += 1,
+			')' => depth = depth.saturating_sub(1),
+			',' if depth == 0 => {
+				split.push(arguments[start..index].trim());
+				start = index + 1;
+			}
+			_ => (),
+		}
+	}
+	split.push(arguments[start..].trim());
+
+	split
+}
+
+/// Find the directive that ends a body which starts at the beginning of
+/// `text`, which is the first of `closers` that isn't inside something
+/// nested. Directives named in `openers` start a body of their own that
+/// needs an `!end` first. Returns the offset of the `!` and the name.
+pub(crate) fn find_closer<'a>(
+	text: &str,
+	openers: &[&str],
+	closers: &[&'a str],
+) -> Option<(usize, &'a str)> {
+	let mut depth = 0usize;
+
+	let mut scanner = Scanner::new(text);
+	while let Some((index, c)) = scanner.next_code() {
+		if c != '!' {
+			continue;
+		}
+
+		let rest = &text[index + 1..];
+		let name = &rest[..rest.find(|c| !is_name_character(c)).unwrap_or(rest.len())];
+		if depth == 0 {
+			if let Some(closer) = closers.iter().find(|closer| **closer == name) {
+				return Some((index, closer));
+			}
+		}
+
+		match name {
+			"end" => depth = depth.saturating_sub(1),
+			name if openers.contains(&name) => depth += 1,
+			_ => (),
+		}
+	}
+
+	None
+}
+
+/// Goes through source text a character at a time, stepping over comments
+/// and string literals
+struct Scanner<'a> {
+	text: &'a str,
+	offset: usize,
+}
+
+impl<'a> Scanner<'a> {
+	fn new(text: &'a str) -> Self {
+		Self { text, offset: 0 }
+	}
+
+	fn next(&mut self) -> Option<(usize, char)> {
+		let c = self.text[self.offset..].chars().next()?;
+		let index = self.offset;
+		self.offset += c.len_utf8();
+
+		Some((index, c))
+	}
+
+	/// The next character that isn't in a comment or string literal, with
+	/// its offset
+	fn next_code(&mut self) -> Option<(usize, char)> {
+		loop {
+			let (index, c) = self.next()?;
+			match c {
+				'#' => self.skip_to(
+					self.text[index..]
+						.find('\n')
+						.map_or(self.text.len(), |i| index + i),
+				),
+				// Literals end at the end of the line even if they're unclosed
+				'"' => {
+					while let Some((_, c)) = self.next() {
+						match c {
+							'\\' => {
+								self.next();
+							}
+							'"' | '\n' => break,
+							_ => (),
+						}
+					}
+				}
+				_ => return Some((index, c)),
+			}
+		}
+	}
+
+	fn skip_to(&mut self, offset: usize) {
+		self.offset = offset;
+	}
+}
@@ -0,0 +1,80 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Finds the files named by `!include` and `!embed`. Give one to
+/// [crate::Hext::resolver] to include files from somewhere other than the
+/// filesystem.
+pub trait Resolver {
+	/// Find the file `path` refers to, as it was written in the directive.
+	/// `from` is the name of the file that included it, if it has one.
+	///
+	/// Returns the name of the included file and its contents. Names are
+	/// used to spot include cycles and in errors, so a file should get the
+	/// same name no matter how it's reached.
+	fn resolve(&mut self, path: &str, from: Option<&str>) -> io::Result<(String, String)>;
+
+	/// Find the file `path` refers to and return its bytes, which are put in
+	/// the output as they are. `path` and `from` are as in [Resolver::resolve].
+	fn resolve_bytes(&mut self, path: &str, from: Option<&str>) -> io::Result<Vec<u8>>;
+
+	/// Whether two names are the same file, which is how include cycles are
+	/// found. One may be the name the main file was given rather than one
+	/// from [Resolver::resolve]. By default they have to be equal.
+	fn same_file(&self, a: &str, b: &str) -> bool {
+		a == b
+	}
+}
+
+/// Reads included files from the filesystem. Paths are relative to the
+/// directory of the file that included them, or the current directory if
+/// that file has no name.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileResolver;
+
+impl FileResolver {
+	fn path(path: &str, from: Option<&str>) -> PathBuf {
+		let joined = match from.and_then(|from| Path::new(from).parent()) {
+			Some(directory) => directory.join(path),
+			None => PathBuf::from(path),
+		};
+
+		// Tidy away the . and .. so that a file has one name however it was
+		// reached. We can't canonicalize as that would make every name absolute.
+		let mut tidy = PathBuf::new();
+		for component in joined.components() {
+			match component {
+				Component::CurDir => (),
+				Component::ParentDir
+					if matches!(tidy.components().next_back(), Some(Component::Normal(_))) =>
+				{
+					tidy.pop();
+				}
+				component => tidy.push(component),
+			}
+		}
+
+		tidy
+	}
+}
+
+impl Resolver for FileResolver {
+	fn resolve(&mut self, path: &str, from: Option<&str>) -> io::Result<(String, String)> {
+		let path = Self::path(path, from);
+		let contents = std::fs::read_to_string(&path)?;
+
+		Ok((path.display().to_string(), contents))
+	}
+
+	fn resolve_bytes(&mut self, path: &str, from: Option<&str>) -> io::Result<Vec<u8>> {
+		std::fs::read(Self::path(path, from))
+	}
+
+	/// The main file is named however it was typed, so compare where the
+	/// names actually lead
+	fn same_file(&self, a: &str, b: &str) -> bool {
+		match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+			(Ok(a), Ok(b)) => a == b,
+			_ => a == b,
+		}
+	}
+}
@@ -0,0 +1,651 @@
+use crate::checksum::Checksum;
+use crate::decompile::{is_printable, literal, octets};
+use std::ops::Range;
+
+/// A file format the [crate::Decompiler] can lay out field by field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+	Gif,
+	Png,
+	Bmp,
+	Wav,
+	/// Just the ELF header. Everything after it is left as rows.
+	Elf,
+	/// An IPv4 packet, and the UDP header if it carries one
+	Ipv4,
+}
+
+/// How a format lays out some bytes
+pub(crate) struct Layout {
+	pub format: Format,
+	pub little_endian: bool,
+	pub items: Vec<Item>,
+	/// How many bytes from the start the items cover
+	pub length: usize,
+}
+
+pub(crate) enum Item {
+	/// A comment on its own line, after a blank one
+	Section(String),
+	Field {
+		token: String,
+		comment: String,
+	},
+	/// The start of a block, like `u8{` or `@ip{`
+	Open {
+		token: String,
+		comment: String,
+	},
+	Close,
+	/// Bytes with nothing to say about them, which are written as rows
+	Raw(Range<usize>),
+}
+
+impl Format {
+	pub const ALL: [Format; 6] = [
+		Format::Gif,
+		Format::Png,
+		Format::Bmp,
+		Format::Wav,
+		Format::Elf,
+		Format::Ipv4,
+	];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Format::Gif => "gif",
+			Format::Png => "png",
+			Format::Bmp => "bmp",
+			Format::Wav => "wav",
+			Format::Elf => "elf",
+			Format::Ipv4 => "ipv4",
+		}
+	}
+
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::ALL
+			.into_iter()
+			.find(|format| format.name().eq_ignore_ascii_case(name))
+	}
+
+	/// The format `bytes` look to be in, going by their signature. IPv4 has
+	/// none, so a packet needs a sensible header with a valid checksum.
+	pub fn detect(bytes: &[u8]) -> Option<Self> {
+		if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+			Some(Format::Gif)
+		} else if bytes.starts_with(b"\x89PNG\r\n\x1A\n") {
+			Some(Format::Png)
+		} else if bytes.starts_with(b"BM")
+			&& bytes.len() >= 18
+			&& [12, 40, 52, 56, 64, 108, 124].contains(&le(&bytes[14..18]))
+		{
+			Some(Format::Bmp)
+		} else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+			Some(Format::Wav)
+		} else if bytes.starts_with(b"\x7FELF") {
+			Some(Format::Elf)
+		} else if ipv4_header(bytes).is_some_and(|(_, valid)| valid)
+			&& be(&bytes[2..4]) == bytes.len() as u64
+		{
+			Some(Format::Ipv4)
+		} else {
+			None
+		}
+	}
+
+	pub(crate) fn describe(&self, bytes: &[u8]) -> Layout {
+		let little_endian = match self {
+			Format::Png | Format::Ipv4 => false,
+			// EI_DATA is 2 for big-endian
+			Format::Elf => bytes.get(5) != Some(&2),
+			Format::Gif | Format::Bmp | Format::Wav => true,
+		};
+
+		let mut writer = Writer {
+			bytes,
+			offset: 0,
+			little_endian,
+			items: vec![],
+			depth: 0,
+		};
+
+		// Formats stop wherever the bytes stop making sense to them
+		let _ = match self {
+			Format::Gif => gif(&mut writer),
+			Format::Png => png(&mut writer),
+			Format::Bmp => bmp(&mut writer),
+			Format::Wav => wav(&mut writer),
+			Format::Elf => elf(&mut writer),
+			Format::Ipv4 => ipv4(&mut writer),
+		};
+		for _ in 0..writer.depth {
+			writer.items.push(Item::Close);
+		}
+
+		Layout {
+			format: *self,
+			little_endian,
+			items: writer.items,
+			length: writer.offset,
+		}
+	}
+}
+
+/// Goes through the bytes a field at a time. Every method returns None
+/// without taking anything if there aren't enough bytes left.
+struct Writer<'a> {
+	bytes: &'a [u8],
+	offset: usize,
+	little_endian: bool,
+	items: Vec<Item>,
+	/// How many blocks are open
+	depth: usize,
+}
+
+impl<'a> Writer<'a> {
+	fn remaining(&self) -> usize {
+		self.bytes.len() - self.offset
+	}
+
+	/// `length` bytes starting `at` bytes past the offset, without taking them
+	fn peek(&self, at: usize, length: usize) -> Option<&'a [u8]> {
+		let start = self.offset.checked_add(at)?;
+		self.bytes.get(start..start.checked_add(length)?)
+	}
+
+	fn peek_uint(&self, at: usize, length: usize) -> Option<u64> {
+		self.peek(at, length).map(|bytes| self.number(bytes))
+	}
+
+	fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+		let bytes = self.peek(0, length)?;
+		self.offset += length;
+		Some(bytes)
+	}
+
+	fn number(&self, bytes: &[u8]) -> u64 {
+		match self.little_endian {
+			true => le(bytes),
+			false => be(bytes),
+		}
+	}
+
+	fn section(&mut self, title: &str) {
+		self.items.push(Item::Section(title.to_string()));
+	}
+
+	/// A field that's written as `token`, which is the next `length` bytes
+	fn token(&mut self, length: usize, token: String, comment: &str) -> Option<&'a [u8]> {
+		let bytes = self.take(length)?;
+		self.items.push(Item::Field {
+			token,
+			comment: comment.to_string(),
+		});
+		Some(bytes)
+	}
+
+	/// An unsigned integer in the header's byte order, like `u16=4`
+	fn uint(&mut self, length: usize, comment: &str) -> Option<u64> {
+		let value = self.peek_uint(0, length)?;
+		self.token(length, format!("u{}={}", length * 8, value), comment)?;
+		Some(value)
+	}
+
+	/// A signed integer in the header's byte order, like `i32=-4`
+	fn int(&mut self, length: usize, comment: &str) -> Option<i64> {
+		let shift = 64 - length as u32 * 8;
+		let value = ((self.peek_uint(0, length)? << shift) as i64) >> shift;
+		self.token(length, format!("i{}={}", length * 8, value), comment)?;
+		Some(value)
+	}
+
+	/// Bytes as hex
+	fn octets(&mut self, length: usize, comment: &str) -> Option<&'a [u8]> {
+		let token = octets(self.peek(0, length)?);
+		self.token(length, token, comment)
+	}
+
+	/// Bytes as a string literal, or as hex if they aren't all printable
+	fn text(&mut self, length: usize, comment: &str) -> Option<&'a [u8]> {
+		let bytes = self.peek(0, length)?;
+		let token = match bytes.iter().all(|byte| is_printable(*byte)) {
+			true => literal(bytes),
+			false => octets(bytes),
+		};
+		self.token(length, token, comment)
+	}
+
+	/// Hex with runs of three or more visible characters as literals, which
+	/// suits signatures like `89 "PNG" 0D 0A 1A 0A`
+	fn signature(&mut self, length: usize, comment: &str) -> Option<&'a [u8]> {
+		let bytes = self.peek(0, length)?;
+
+		let mut tokens = vec![];
+		let mut rest = bytes;
+		while !rest.is_empty() {
+			let text = rest
+				.iter()
+				.take_while(|byte| byte.is_ascii_graphic())
+				.count();
+			let (token, after) = match text {
+				3.. => (literal(&rest[..text]), &rest[text..]),
+				_ => (octets(&rest[..1]), &rest[1..]),
+			};
+			tokens.push(token);
+			rest = after;
+		}
+
+		self.token(length, tokens.join(" "), comment)
+	}
+
+	/// Each byte as an unsigned decimal, like `=255 =0 =0`
+	fn decimals(&mut self, length: usize, comment: &str) -> Option<&'a [u8]> {
+		let bytes = self.peek(0, length)?;
+		let decimals: Vec<String> = bytes.iter().map(|byte| format!("={}", byte)).collect();
+		self.token(length, decimals.join(" "), comment)
+	}
+
+	/// Fields packed into whole bytes, most significant bit first, each
+	/// given as its width and a comment. Single bits are written as `.1`.
+	fn bits(&mut self, fields: &[(u32, &str)]) -> Option<Vec<u64>> {
+		let width: u32 = fields.iter().map(|(width, _)| width).sum();
+		debug_assert!(width.is_multiple_of(8) && width <= 64);
+
+		let packed = be(self.take(width as usize / 8)?);
+		let mut values = vec![];
+		let mut shift = width;
+		for (width, comment) in fields {
+			shift -= width;
+			let value = (packed >> shift) & ((1 << width) - 1);
+
+			// Wider fields are split into octets that would otherwise follow
+			// the header's byte order, but these run most significant first
+			let token = match (width, self.little_endian) {
+				(1, _) => format!(".{}", value),
+				(9.., true) => format!("u{}be={}", width, value),
+				_ => format!("u{}={}", width, value),
+			};
+			self.items.push(Item::Field {
+				token,
+				comment: comment.to_string(),
+			});
+			values.push(value);
+		}
+
+		Some(values)
+	}
+
+	/// Start a block, taking the `length` bytes its token stands for. Callers
+	/// check that what goes in it is there first.
+	fn open(&mut self, length: usize, token: String, comment: &str) -> Option<()> {
+		self.take(length)?;
+		self.items.push(Item::Open {
+			token,
+			comment: comment.to_string(),
+		});
+		self.depth += 1;
+		Some(())
+	}
+
+	fn close(&mut self) {
+		self.items.push(Item::Close);
+		self.depth -= 1;
+	}
+
+	fn raw(&mut self, length: usize) -> Option<()> {
+		let start = self.offset;
+		self.take(length)?;
+		if length > 0 {
+			self.items.push(Item::Raw(start..self.offset));
+		}
+		Some(())
+	}
+
+	fn rest(&mut self) {
+		let _ = self.raw(self.remaining());
+	}
+}
+
+fn le(bytes: &[u8]) -> u64 {
+	bytes
+		.iter()
+		.rev()
+		.fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+fn be(bytes: &[u8]) -> u64 {
+	bytes
+		.iter()
+		.fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+fn gif(w: &mut Writer) -> Option<()> {
+	w.text(6, "Signature and version")?;
+
+	w.section("Logical Screen Descriptor");
+	w.uint(2, "Canvas width")?;
+	w.uint(2, "Canvas height")?;
+	let packed = w.bits(&[
+		(1, "Global Color Table flag"),
+		(3, "Color resolution"),
+		(1, "Sort flag"),
+		(3, "Size of the Global Color Table. 2^(this + 1) colors"),
+	])?;
+	w.octets(1, "Background color index")?;
+	w.octets(1, "Pixel aspect ratio")?;
+
+	if packed[0] == 1 {
+		w.section("Global Color Table");
+		gif_colors(w, packed[3])?;
+	}
+
+	loop {
+		match w.peek(0, 1)?[0] {
+			b',' => {
+				w.section("Image Descriptor");
+				w.text(1, "Image separator")?;
+				w.uint(2, "Left")?;
+				w.uint(2, "Top")?;
+				w.uint(2, "Width")?;
+				w.uint(2, "Height")?;
+				let packed = w.bits(&[
+					(1, "Local Color Table flag"),
+					(1, "Interlace flag"),
+					(1, "Sort flag"),
+					(2, "Reserved"),
+					(3, "Size of the Local Color Table. 2^(this + 1) colors"),
+				])?;
+
+				if packed[0] == 1 {
+					w.section("Local Color Table");
+					gif_colors(w, packed[4])?;
+				}
+
+				w.section("Image Data");
+				w.octets(1, "LZW minimum code size")?;
+				gif_sub_blocks(w)?;
+			}
+			b'!' => {
+				let label = w.peek(1, 1)?[0];
+				w.section(match label {
+					0xF9 => "Graphic Control Extension",
+					0xFE => "Comment Extension",
+					0xFF => "Application Extension",
+					0x01 => "Plain Text Extension",
+					_ => "Extension",
+				});
+				w.text(1, "Extension introducer")?;
+				w.octets(1, "Label")?;
+
+				if label == 0xF9 && w.peek(0, 1)? == [4] && w.peek(0, 6).is_some() {
+					w.open(1, "u8{".to_string(), "Block size")?;
+					w.bits(&[
+						(3, "Reserved"),
+						(3, "Disposal method"),
+						(1, "User input flag"),
+						(1, "Transparent color flag"),
+					])?;
+					w.uint(2, "Delay in hundredths of a second")?;
+					w.octets(1, "Transparent color index")?;
+					w.close();
+				}
+				gif_sub_blocks(w)?;
+			}
+			b';' => {
+				w.section("Trailer");
+				w.text(1, "End of the GIF")?;
+				return Some(());
+			}
+			_ => return None,
+		}
+	}
+}
+
+fn gif_colors(w: &mut Writer, size: u64) -> Option<()> {
+	for color in 0..2 << size {
+		w.decimals(3, &format!("Color {}", color))?;
+	}
+	Some(())
+}
+
+/// Blocks of data that start with their length, up to an empty one
+fn gif_sub_blocks(w: &mut Writer) -> Option<()> {
+	loop {
+		let size = w.peek(0, 1)?[0] as usize;
+		if size == 0 {
+			w.octets(1, "Block terminator")?;
+			return Some(());
+		}
+
+		w.peek(1, size)?;
+		w.open(1, "u8{".to_string(), "")?;
+		w.raw(size)?;
+		w.close();
+	}
+}
+
+fn png(w: &mut Writer) -> Option<()> {
+	w.signature(8, "Signature")?;
+
+	let crc32 = Checksum::from_name("crc32")?;
+	for index in 0.. {
+		let length = w.peek_uint(0, 4)? as usize;
+		let kind = w.peek(4, 4)?;
+		let stored = w.peek_uint(8 + length, 4)?;
+		let checked = w.peek(4, 4 + length)?;
+
+		w.section(&format!("{} chunk", String::from_utf8_lossy(kind)));
+		w.uint(4, "Length")?;
+
+		// With a good CRC the hext can work it out itself
+		let label = format!("chunk{}", index);
+		let valid = crc32.compute(checked) == stored as u128;
+		if valid {
+			w.open(0, format!("@{}{{", label), "")?;
+		}
+
+		w.text(4, "Chunk type")?;
+		match kind {
+			b"IHDR" if length == 13 => {
+				w.uint(4, "Width")?;
+				w.uint(4, "Height")?;
+				w.uint(1, "Bit depth")?;
+				w.uint(1, "Color type")?;
+				w.uint(1, "Compression method")?;
+				w.uint(1, "Filter method")?;
+				w.uint(1, "Interlace method")?;
+			}
+			_ => w.raw(length)?,
+		}
+
+		if valid {
+			w.close();
+			w.token(4, format!("!crc32(@{})", label), "CRC")?;
+		} else {
+			w.uint(4, "CRC, which doesn't match")?;
+		}
+
+		if kind == b"IEND" {
+			break;
+		}
+	}
+
+	Some(())
+}
+
+fn bmp(w: &mut Writer) -> Option<()> {
+	w.section("Bitmap file header");
+	w.text(2, "Signature")?;
+	w.uint(4, "File size")?;
+	w.octets(4, "Reserved")?;
+	let pixels = w.uint(4, "Offset of the pixel data")? as usize;
+
+	w.section("DIB header");
+	let size = w.peek_uint(0, 4)? as usize;
+	w.uint(4, "Header size")?;
+	if size >= 40 {
+		w.int(4, "Width")?;
+		w.int(4, "Height, which is negative for top down")?;
+		w.uint(2, "Color planes")?;
+		w.uint(2, "Bits per pixel")?;
+		w.uint(4, "Compression")?;
+		w.uint(4, "Image size")?;
+		w.int(4, "Horizontal resolution in pixels per metre")?;
+		w.int(4, "Vertical resolution in pixels per metre")?;
+		w.uint(4, "Colors in the color table")?;
+		w.uint(4, "Important colors")?;
+		w.raw(size - 40)?;
+	} else {
+		w.raw(size.checked_sub(4)?)?;
+	}
+
+	if pixels > w.offset {
+		w.section("Color table and gap");
+		w.raw(pixels - w.offset)?;
+	}
+
+	if w.remaining() > 0 {
+		w.section("Pixel data");
+		w.rest();
+	}
+
+	Some(())
+}
+
+fn wav(w: &mut Writer) -> Option<()> {
+	w.section("RIFF header");
+	w.text(4, "Chunk ID")?;
+	w.uint(4, "Size of everything after this")?;
+	w.text(4, "Format")?;
+
+	while w.remaining() > 0 {
+		let id = w.peek(0, 4)?;
+		let size = w.peek_uint(4, 4)? as usize;
+		w.peek(8, size)?;
+
+		w.section(&format!("{} chunk", literal(id)));
+		w.text(4, "Chunk ID")?;
+		w.uint(4, "Chunk size")?;
+		match id {
+			b"fmt " if size >= 16 => {
+				w.uint(2, "Audio format, which is 1 for PCM")?;
+				w.uint(2, "Channels")?;
+				w.uint(4, "Sample rate")?;
+				w.uint(4, "Byte rate")?;
+				w.uint(2, "Block align")?;
+				w.uint(2, "Bits per sample")?;
+				w.raw(size - 16)?;
+			}
+			_ => w.raw(size)?,
+		}
+
+		// Chunks are padded to an even length
+		if size % 2 == 1 && w.remaining() > 0 {
+			w.octets(1, "Padding")?;
+		}
+	}
+
+	Some(())
+}
+
+fn elf(w: &mut Writer) -> Option<()> {
+	w.section("ELF identification");
+	w.signature(4, "Magic")?;
+	let class = w.uint(1, "Class, which is 1 for 32-bit and 2 for 64-bit")?;
+	w.uint(1, "Data, which is 1 for little-endian and 2 for big-endian")?;
+	w.uint(1, "Version")?;
+	w.uint(1, "OS ABI")?;
+	w.uint(1, "ABI version")?;
+	w.octets(7, "Padding")?;
+
+	let address = match class {
+		1 => 4,
+		2 => 8,
+		_ => return None,
+	};
+
+	w.section("ELF header");
+	w.uint(2, "Type")?;
+	w.uint(2, "Machine")?;
+	w.uint(4, "Version")?;
+	w.uint(address, "Entry point")?;
+	w.uint(address, "Program header table offset")?;
+	w.uint(address, "Section header table offset")?;
+	w.uint(4, "Flags")?;
+	w.uint(2, "ELF header size")?;
+	w.uint(2, "Program header table entry size")?;
+	w.uint(2, "Program header table entries")?;
+	w.uint(2, "Section header table entry size")?;
+	w.uint(2, "Section header table entries")?;
+	w.uint(2, "Index of the section names")?;
+
+	if w.remaining() > 0 {
+		w.section("The rest of the file");
+		w.rest();
+	}
+
+	Some(())
+}
+
+/// The length of the IPv4 header at the start of `bytes` and whether its
+/// checksum is right, if it looks like one
+fn ipv4_header(bytes: &[u8]) -> Option<(usize, bool)> {
+	let version = bytes.first()? >> 4;
+	let length = (bytes.first()? & 0xF) as usize * 4;
+	if version != 4 || length < 20 {
+		return None;
+	}
+
+	let mut header = bytes.get(..length)?.to_vec();
+	let stored = be(&header[10..12]);
+	header[10..12].fill(0);
+
+	let valid = Checksum::Internet.compute(&header) == stored as u128;
+	Some((length, valid))
+}
+
+fn ipv4(w: &mut Writer) -> Option<()> {
+	let (length, valid) = ipv4_header(w.bytes)?;
+
+	w.section("IPv4 header");
+	if valid {
+		w.open(0, "@ip{".to_string(), "")?;
+	}
+	w.bits(&[(4, "Version"), (4, "Header length in 32-bit words")])?;
+	w.bits(&[(6, "DSCP"), (2, "ECN")])?;
+	w.uint(2, "Total length")?;
+	w.uint(2, "Identification")?;
+	w.bits(&[
+		(1, "Reserved"),
+		(1, "Don't fragment"),
+		(1, "More fragments"),
+		(13, "Fragment offset"),
+	])?;
+	w.uint(1, "Time to live")?;
+	let protocol = w.uint(1, "Protocol, which is 6 for TCP and 17 for UDP")?;
+	match valid {
+		true => w.token(2, "!internet(@ip)".to_string(), "Header checksum")?,
+		false => w.octets(2, "Header checksum, which doesn't match")?,
+	};
+	w.decimals(4, "Source address")?;
+	w.decimals(4, "Destination address")?;
+	w.raw(length - 20)?;
+	if valid {
+		w.close();
+	}
+
+	if protocol == 17 && w.remaining() >= 8 {
+		w.section("UDP header");
+		w.uint(2, "Source port")?;
+		w.uint(2, "Destination port")?;
+		w.uint(2, "Length")?;
+		w.uint(2, "Checksum")?;
+	}
+
+	if w.remaining() > 0 {
+		w.section("Payload");
+		w.rest();
+	}
+
+	Some(())
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::ops::Range;
+
+/// A region of the source text. `line` and `column` are where the region
+/// starts and count from one. Columns are counted in characters, not bytes.
+/// `start` and `end` are the byte offsets of the region.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+	pub line: usize,
+	pub column: usize,
+	pub start: usize,
+	pub end: usize,
+	/// Which source the span is in. Zero is the source given to
+	/// [crate::Hext::parse] and included files count up from there.
+	pub(crate) source: usize,
+}
+
+impl Span {
+	pub fn range(&self) -> Range<usize> {
+		self.start..self.end
+	}
+
+	/// Narrow the span to a byte range of `text`, which must be the single
+	/// line of source text this span covers.
+	pub(crate) fn within(&self, text: &str, range: Range<usize>) -> Span {
+		Span {
+			line: self.line,
+			column: self.column + text[..range.start].chars().count(),
+			start: self.start + range.start,
+			end: self.start + range.end,
+			source: self.source,
+		}
+	}
+}
+
+impl fmt::Display for Span {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.line, self.column)
+	}
+}
+
+/// Walks through the source a character at a time, keeping track of the line
+/// and column so we can say where things went wrong.
+pub(crate) struct Cursor<'a> {
+	source: &'a str,
+	/// The source's number, which goes in every span we hand out
+	id: usize,
+	/// Where `source` starts in the whole source, when it's only part of it
+	base: usize,
+	offset: usize,
+	line: usize,
+	column: usize,
+}
+
+impl<'a> Cursor<'a> {
+	pub fn new(source: &'a str, id: usize) -> Self {
+		Self {
+			source,
+			id,
+			base: 0,
+			offset: 0,
+			line: 1,
+			column: 1,
+		}
+	}
+
+	/// A cursor over `text`, which is the part of a source that `span` covers.
+	/// Its spans are as if it were walking the whole source.
+	pub fn slice(text: &'a str, span: Span) -> Self {
+		Self {
+			source: text,
+			id: span.source,
+			base: span.start,
+			offset: 0,
+			line: span.line,
+			column: span.column,
+		}
+	}
+
+	/// The text a span from this cursor covers
+	pub fn text(&self, span: Span) -> &'a str {
+		&self.source[span.start - self.base..span.end - self.base]
+	}
+
+	pub fn peek(&self) -> Option<char> {
+		self.rest().chars().next()
+	}
+
+	/// Everything that hasn't been consumed yet
+	pub fn rest(&self) -> &'a str {
+		&self.source[self.offset..]
+	}
+
+	pub fn next_if<F: FnOnce(char) -> bool>(&mut self, func: F) -> Option<char> {
+		match self.peek() {
+			Some(c) if func(c) => self.next(),
+			_ => None,
+		}
+	}
+
+	/// An empty span at the current position. Pass it to [Cursor::span_from]
+	/// to get the span of everything consumed in between.
+	pub fn mark(&self) -> Span {
+		Span {
+			line: self.line,
+			column: self.column,
+			start: self.base + self.offset,
+			end: self.base + self.offset,
+			source: self.id,
+		}
+	}
+
+	pub fn span_from(&self, mark: Span) -> Span {
+		Span {
+			end: self.base + self.offset,
+			..mark
+		}
+	}
+}
+
+impl Iterator for Cursor<'_> {
+	type Item = char;
+
+	fn next(&mut self) -> Option<char> {
+		let c = self.peek()?;
+		self.offset += c.len_utf8();
+
+		if c == '\n' {
+			self.line += 1;
+			self.column = 1;
+		} else {
+			self.column += 1;
+		}
+
+		Some(c)
+	}
+}
@@ -0,0 +1,77 @@
+use crate::source::Span;
+use std::ops::Range;
+
+/// Which part of the source each run of output bytes came from, as made by
+/// [crate::Hext::parse_mapped]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+	/// In the order their bytes come in the output
+	pub mappings: Vec<Mapping>,
+}
+
+/// A run of output bytes and where they were written. Bytes that came from
+/// a macro are where they were written in its body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+	/// Runs of bits may share their first and last octets with the mappings
+	/// either side
+	pub bytes: Range<usize>,
+	/// The name of the file, which is None for an unnamed source given to
+	/// [crate::Hext::parse_mapped]
+	pub file: Option<String>,
+	pub span: Span,
+}
+
+impl SourceMap {
+	/// The mapping that the byte at `offset` came from. If it came from more
+	/// than one, like an octet of bit fields, this is the first.
+	pub fn find(&self, offset: usize) -> Option<&Mapping> {
+		self.mappings
+			.iter()
+			.find(|mapping| mapping.bytes.contains(&offset))
+	}
+
+	/// The map as JSON, which is an object with the mappings in a list. Each
+	/// one has the `start` and `end` of its bytes, with the end exclusive, and
+	/// the `file`, `line`, and `column` they came from.
+	pub fn to_json(&self) -> String {
+		let mappings: Vec<String> = self
+			.mappings
+			.iter()
+			.map(|mapping| {
+				let file = match &mapping.file {
+					Some(file) => Self::json_string(file),
+					None => "null".to_string(),
+				};
+
+				format!(
+					"\t\t{{\"start\": {}, \"end\": {}, \"file\": {}, \"line\": {}, \"column\": {}}}",
+					mapping.bytes.start, mapping.bytes.end, file, mapping.span.line, mapping.span.column
+				)
+			})
+			.collect();
+
+		match mappings.is_empty() {
+			true => "{\n\t\"mappings\": []\n}\n".to_string(),
+			false => format!("{{\n\t\"mappings\": [\n{}\n\t]\n}}\n", mappings.join(",\n")),
+		}
+	}
+
+	fn json_string(string: &str) -> String {
+		let mut json = String::from("\"");
+		for c in string.chars() {
+			match c {
+				'"' => json.push_str("\\\""),
+				'\\' => json.push_str("\\\\"),
+				'\n' => json.push_str("\\n"),
+				'\r' => json.push_str("\\r"),
+				'\t' => json.push_str("\\t"),
+				c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+				c => json.push(c),
+			}
+		}
+		json.push('"');
+
+		json
+	}
+}
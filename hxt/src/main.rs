@@ -1,13 +1,15 @@
 use getopts::Options;
-use hext;
-use hext::Hext;
+use hext::{Decompiler, Format, Formatter, Hext, SourceMap, Span};
 use std::env;
 use std::fs;
 use std::fs::File;
 use std::io::{self, Read, Write};
 
 fn print_usage(program: &str, opts: Options) {
-	let brief = format!("Usage: {} [options] FILES", program);
+	let brief = format!(
+		"Usage: {0} [options] FILES\n       {0} [options] decompile FILES\n       {0} [options] fmt FILES",
+		program
+	);
 	println!("{}", opts.usage(&brief));
 }
 
@@ -16,6 +18,44 @@ fn main() {
 
 	let mut opts = Options::new();
 	opts.optopt("o", "output", "output to a file", "FILE");
+	opts.optmulti(
+		"D",
+		"define",
+		"define a constant, which is 1 if there's no value",
+		"NAME[=VALUE]",
+	);
+	opts.optopt(
+		"w",
+		"width",
+		"bytes per row when decompiling, which is 16 by default",
+		"N",
+	);
+	opts.optopt(
+		"f",
+		"format",
+		"decompile as gif, png, bmp, wav, elf, or ipv4 rather than going by the \
+		 bytes, or as raw for plain rows",
+		"NAME",
+	);
+	opts.optopt(
+		"g",
+		"group",
+		"octets per group when formatting, rather than as they were written",
+		"N",
+	);
+	opts.optflag("i", "in-place", "rewrite the files when formatting");
+	opts.optflagopt(
+		"m",
+		"map",
+		"write a JSON source map to FILE, or next to the output as OUTPUT.map",
+		"FILE",
+	);
+	opts.optopt(
+		"",
+		"offset",
+		"print where the byte at offset N came from instead of the output",
+		"N",
+	);
 	opts.optflag("h", "help", "print this message and exit");
 
 	// Get matches for all arguments passed, excluing the program name which is args[0]
@@ -29,45 +69,349 @@ fn main() {
 		return;
 	}
 
-	let mut outfile = if let Some(filename) = matches.opt_str("o") {
-		match File::create(filename) {
-			Ok(f) => Some(f),
-			Err(e) => {
-				eprintln!("hext: {}", e);
+	let map_path = match (matches.opt_present("m"), matches.opt_str("m")) {
+		(_, Some(path)) => Some(path),
+		(true, None) => match matches.opt_str("o") {
+			Some(output) => Some(format!("{}.map", output)),
+			None => {
+				eprintln!("hext: --map needs a FILE when the output isn't a file");
 				std::process::exit(1);
 			}
-		}
-	} else {
-		None
+		},
+		(false, None) => None,
 	};
 
+	let offset = matches.opt_str("offset").map(|offset| {
+		match Hext::parse_integer(&offset).and_then(|offset| usize::try_from(offset).ok()) {
+			Some(offset) => offset,
+			None => {
+				eprintln!("hext: '{}' is not a valid offset", offset);
+				std::process::exit(1);
+			}
+		}
+	});
+
+	let output = matches.opt_str("o");
+
+	let mut defines = vec![];
+	for define in matches.opt_strs("D") {
+		match parse_define(&define) {
+			Some(define) => defines.push(define),
+			None => {
+				eprintln!("hext: '{}' is not a valid define. Try NAME=VALUE", define);
+				std::process::exit(1);
+			}
+		}
+	}
+
+	if let Some(("decompile", files)) = matches
+		.free
+		.split_first()
+		.map(|(command, files)| (command.as_str(), files))
+	{
+		let mut outfile = create_output(output.as_deref());
+		let mut decompiler = Decompiler::new();
+		if let Some(width) = matches.opt_str("w") {
+			match width.parse() {
+				Ok(width) => decompiler = decompiler.width(width),
+				Err(_) => {
+					eprintln!("hext: '{}' is not a valid width", width);
+					std::process::exit(1);
+				}
+			}
+		}
+
+		match matches.opt_str("f").as_deref() {
+			Some("raw") => decompiler = decompiler.detect(false),
+			Some(name) => match Format::from_name(name) {
+				Some(format) => decompiler = decompiler.format(format),
+				None => {
+					eprintln!("hext: '{}' is not a format hext knows", name);
+					std::process::exit(1);
+				}
+			},
+			None => (),
+		}
+
+		if files.is_empty() {
+			let mut bytes = vec![];
+			io::stdin().read_to_end(&mut bytes).unwrap();
+
+			do_decompile(&decompiler, &bytes, &mut outfile);
+		} else {
+			for file in files {
+				match fs::read(file) {
+					Ok(bytes) => do_decompile(&decompiler, &bytes, &mut outfile),
+					Err(e) => eprintln!("hext: {}", e),
+				}
+			}
+		}
+
+		return;
+	}
+
+	if let Some(("fmt", files)) = matches
+		.free
+		.split_first()
+		.map(|(command, files)| (command.as_str(), files))
+	{
+		let mut outfile = create_output(output.as_deref());
+		let mut formatter = Formatter::new();
+		if let Some(group) = matches.opt_str("g") {
+			match group.parse() {
+				Ok(group) => formatter = formatter.group(group),
+				Err(_) => {
+					eprintln!("hext: '{}' is not a valid group size", group);
+					std::process::exit(1);
+				}
+			}
+		}
+
+		let mut formatted = true;
+		if files.is_empty() {
+			let mut raw = String::new();
+			io::stdin().read_to_string(&mut raw).unwrap();
+
+			formatted = do_fmt(&formatter, "<stdin>", &raw, &mut outfile, false);
+		} else {
+			for file in files {
+				match fs::read_to_string(file) {
+					Ok(raw) => {
+						formatted &= do_fmt(
+							&formatter,
+							file,
+							&raw,
+							&mut outfile,
+							matches.opt_present("i"),
+						)
+					}
+					Err(e) => {
+						eprintln!("hext: {}", e);
+						formatted = false;
+					}
+				}
+			}
+		}
+
+		if !formatted {
+			std::process::exit(1);
+		}
+		return;
+	}
+
 	let files = matches.free.as_slice();
-	if files.len() == 0 {
+	let mut sources = vec![];
+	if files.is_empty() {
 		let mut raw = String::new();
 		io::stdin().read_to_string(&mut raw).unwrap();
 
-		do_hext(&raw, &mut outfile);
+		sources.push(("<stdin>".to_string(), raw));
 	} else {
 		for file in files {
-			let raw = match fs::read_to_string(file) {
-				Ok(raw) => raw,
-				Err(e) => {
-					eprintln!("hext: {}", e);
-					continue;
-				}
+			match fs::read_to_string(file) {
+				Ok(raw) => sources.push((file.clone(), raw)),
+				Err(e) => eprintln!("hext: {}", e),
+			}
+		}
+	}
+
+	// Looking up an offset prints where it came from, so there's no output to create
+	let mut outfile = match offset {
+		Some(_) => None,
+		None => create_output(output.as_deref()),
+	};
+
+	let mapped = map_path.is_some() || offset.is_some();
+	let mut map = SourceMap::default();
+	let mut written = 0;
+	for (name, raw) in &sources {
+		let Some((bytes, file_map)) = do_hext(name, raw, &defines, mapped) else {
+			continue;
+		};
+
+		// The files' bytes go one after another, and so do their maps
+		map.mappings
+			.extend(file_map.mappings.into_iter().map(|mut mapping| {
+				mapping.bytes = mapping.bytes.start + written..mapping.bytes.end + written;
+				mapping
+			}));
+		written += bytes.len();
+
+		if offset.is_none() {
+			match outfile.as_mut() {
+				Some(f) => f.write_all(&bytes).unwrap(),
+				None => io::stdout().write_all(&bytes).unwrap(),
+			}
+		}
+	}
+
+	if let Some(path) = map_path {
+		if let Err(e) = fs::write(path, map.to_json()) {
+			eprintln!("hext: {}", e);
+		}
+	}
+
+	if let Some(offset) = offset {
+		let Some(mapping) = map.find(offset) else {
+			eprintln!("hext: nothing was written at offset {}", offset);
+			std::process::exit(1);
+		};
+
+		let file = mapping.file.as_deref().unwrap_or_default();
+		let source = match sources.iter().find(|(name, _)| name == file) {
+			Some((_, raw)) => raw.clone(),
+			None => fs::read_to_string(file).unwrap_or_default(),
+		};
+		println!(
+			"{}:{}\n{}",
+			file,
+			mapping.span,
+			excerpt(&source, mapping.span)
+		);
+	}
+}
+
+/// Create the file to write to, if there is one, exiting if we can't
+fn create_output(path: Option<&str>) -> Option<File> {
+	let path = path?;
+	match File::create(path) {
+		Ok(f) => Some(f),
+		Err(e) => {
+			eprintln!("hext: {}", e);
+			std::process::exit(1);
+		}
+	}
+}
+
+fn do_decompile(decompiler: &Decompiler, bytes: &[u8], outfile: &mut Option<File>) {
+	let hext = decompiler.decompile(bytes);
+
+	match outfile.as_mut() {
+		Some(f) => f.write_all(hext.as_bytes()).unwrap(),
+		None => io::stdout().write_all(hext.as_bytes()).unwrap(),
+	}
+}
+
+/// Format a file, writing it back over itself if `in_place`. Returns whether
+/// it could be, having printed why not if it couldn't.
+fn do_fmt(
+	formatter: &Formatter,
+	name: &str,
+	raw: &str,
+	outfile: &mut Option<File>,
+	in_place: bool,
+) -> bool {
+	let formatted = match formatter.format(raw) {
+		Ok(formatted) => formatted,
+		Err(e) => {
+			eprintln!("hext: {}:{}\n{}", name, e, excerpt(raw, e.span()));
+			return false;
+		}
+	};
+
+	if in_place {
+		if formatted != raw {
+			if let Err(e) = fs::write(name, formatted) {
+				eprintln!("hext: {}", e);
+				return false;
+			}
+		}
+		return true;
+	}
+
+	match outfile.as_mut() {
+		Some(f) => f.write_all(formatted.as_bytes()).unwrap(),
+		None => io::stdout().write_all(formatted.as_bytes()).unwrap(),
+	}
+	true
+}
+
+/// Parse a define like NAME=VALUE or just NAME
+fn parse_define(define: &str) -> Option<(String, i128)> {
+	let (name, value) = match define.split_once('=') {
+		Some((name, value)) => (name.trim(), value.trim()),
+		None => (define.trim(), "1"),
+	};
+
+	if !Hext::is_constant_name(name) {
+		return None;
+	}
+
+	Some((name.to_string(), Hext::parse_integer(value)?))
+}
+
+/// Parse a file, printing any error. The source map is only filled in if
+/// it's `mapped`.
+fn do_hext(
+	name: &str,
+	raw: &str,
+	defines: &[(String, i128)],
+	mapped: bool,
+) -> Option<(Vec<u8>, SourceMap)> {
+	let hext = defines
+		.iter()
+		.fold(Hext::new().name(name), |hext, (name, value)| {
+			hext.define(name.clone(), *value)
+		});
+
+	let parsed = match mapped {
+		true => hext.parse_mapped(raw),
+		false => hext.parse(raw).map(|bytes| (bytes, SourceMap::default())),
+	};
+
+	match parsed {
+		Ok(parsed) => Some(parsed),
+		Err(e) => {
+			// Errors in included files need that file's source for the excerpt
+			let source = match e.file() {
+				Some(file) if file != name => fs::read_to_string(file).unwrap_or_default(),
+				_ => raw.to_string(),
 			};
+			eprintln!("hext: {}\n{}", e, excerpt(&source, e.span()));
+
+			for include in e.includes().iter().rev() {
+				let file = include.file.as_deref().unwrap_or(name);
+				match &include.macro_name {
+					Some(macro_name) => eprintln!(
+						"  in macro {} invoked at {}:{}",
+						macro_name, file, include.span
+					),
+					None => eprintln!("  included from {}:{}", file, include.span),
+				}
+			}
 
-			do_hext(&raw, &mut outfile);
+			None
 		}
 	}
 }
 
-fn do_hext(raw: &str, outfile: &mut Option<File>) {
-	match Hext::new().parse(raw) {
-		Ok(bytes) => match outfile.as_mut() {
-			Some(f) => f.write_all(&bytes).unwrap(),
-			None => io::stdout().write_all(&bytes).unwrap(),
-		},
-		Err(e) => eprintln!("hext: {}", e),
+/// The source line a span is on with the span underlined
+fn excerpt(raw: &str, span: Span) -> String {
+	if span.end > raw.len() {
+		// The source changed out from under us
+		return String::new();
 	}
+
+	let line = raw.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+
+	// The span may run past the end of the line, like an unterminated literal
+	// or some bits that span lines. We only underline the first.
+	let line_start = raw[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+	let underlined = &raw[span.start..span.end.min(line_start + line.len()).max(span.start)];
+
+	// Keep tabs so the carets line up with however the terminal draws them
+	let padding: String = line
+		.chars()
+		.take(span.column - 1)
+		.map(|c| if c == '\t' { '\t' } else { ' ' })
+		.collect();
+
+	let gutter = " ".repeat(span.line.to_string().len());
+	format!(
+		"{gutter} |\n{} | {}\n{gutter} | {}{}",
+		span.line,
+		line,
+		padding,
+		"^".repeat(underlined.chars().count().max(1)),
+	)
 }